@@ -0,0 +1,52 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// khal-notify is only a binary, so the description handling is pulled in directly.
+#[allow(dead_code)]
+#[path = "../../src/desc.rs"]
+mod desc;
+
+use desc::{Normalization, StripOrder};
+
+fuzz_target!(|data: &[u8]| {
+    // The first bytes pick the options and the rest is the description.
+    let (settings, description) = match data {
+        [max_chars, flags, link_mode, rest @ ..] => ((*max_chars as usize, *flags, *link_mode), rest),
+        _ => return,
+    };
+    let description = String::from_utf8_lossy(description);
+    let (max_chars, flags, link_mode) = settings;
+
+    let names = desc::STRIP_PRESETS.iter().map(|(name, _)| *name);
+    let options = desc::Options {
+        strip_regexes: desc::strip_presets(names).unwrap(),
+        strip_order: if flags & 1 == 0 {
+            StripOrder::Before
+        } else {
+            StripOrder::After
+        },
+        max_chars: if flags & 32 == 0 {
+            Some(max_chars)
+        } else {
+            None
+        },
+        min_chars: 0,
+        links: flags & 2 == 0,
+        sort_links: flags & 4 != 0,
+        link_separator: "\n".to_owned(),
+        link_mode: match link_mode % 3 {
+            0 => desc::LinkMode::Append,
+            1 => desc::LinkMode::Replace,
+            _ => desc::LinkMode::Inline,
+        },
+        markdown_links: flags & 64 != 0,
+        flatten_whitespace: flags & 128 != 0,
+        markup: desc::Markup::Pango,
+    };
+    let normalization = match flags >> 3 & 3 {
+        0 => Normalization::None,
+        1 => Normalization::Nfc,
+        _ => Normalization::Nfd,
+    };
+    options.shorten(&normalization.apply(&description));
+});
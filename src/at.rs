@@ -0,0 +1,364 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::num::ParseIntError;
+use std::time::Duration;
+use time::{Date, Format, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+
+pub const DEFAULT_FORMAT: &str = "%F %R";
+
+/// Format for an ISO 8601 datetime that doesn't carry its own offset, seconds being optional.
+const ISO_LOCAL_FORMAT: &str = "%FT%T";
+const ISO_LOCAL_FORMAT_NO_SECONDS: &str = "%FT%R";
+
+#[derive(Debug)]
+pub enum Error {
+    Minutes(ParseIntError),
+    Datetime {
+        input: String,
+        format: String,
+        source: time::ParseError,
+    },
+    Phrase(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Minutes(source) => write!(f, "offset is not a number: {}", source),
+            Error::Datetime {
+                input,
+                format,
+                source,
+            } => write!(
+                f,
+                "datetime {:?} does not match format {:?}: {}",
+                input, format, source
+            ),
+            Error::Phrase(phrase) => write!(
+                f,
+                "could not understand {:?}, expected \"today\" or \"tomorrow\" optionally followed \
+                 by a time like 9am, 9:30pm or 14:00, or \"in N minutes|hours|days\"",
+                phrase
+            ),
+        }
+    }
+}
+
+/// Resolves the `AT` argument into the moment to query khal for.
+///
+/// Plain numbers are minutes from now, phrases like `tomorrow 9am` are handled by
+/// [`parse_phrase`], ISO 8601 datetimes (`2024-01-02T09:00:00+09:00`) use their own offset if
+/// they have one, and anything else is a datetime in `format` local to `offset`.
+/// The result is always expressed in `offset`, since that's the local time khal expects.
+pub fn parse_target(at: &str, format: &str, offset: UtcOffset) -> Result<OffsetDateTime, Error> {
+    let now = OffsetDateTime::now_utc().to_offset(offset);
+    if let Some(target) = parse_phrase(at, now) {
+        target
+    } else if at.chars().all(|c| c.is_ascii_digit()) {
+        let minutes = at.parse::<u64>().map_err(Error::Minutes)?;
+        Ok(now + Duration::from_secs(minutes * 60))
+    } else if is_iso(at) {
+        parse_iso(at, offset)
+    } else {
+        PrimitiveDateTime::parse(at, format)
+            .map(|dt| dt.assume_offset(offset))
+            .map_err(|source| Error::Datetime {
+                input: at.to_owned(),
+                format: format.to_owned(),
+                source,
+            })
+    }
+}
+
+/// Reads the targets in an `--at-file`, one per line, leaving out blank lines and `#` comments.
+pub fn read_targets(path: &str) -> Result<Vec<String>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    Ok(contents
+        .lines()
+        .map(|line| {
+            line.split_once('#')
+                .map_or(line, |(target, _)| target)
+                .trim()
+        })
+        .filter(|target| !target.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Resolves the few natural phrases `AT` understands against `now`, or `None` if `at` isn't one.
+///
+/// These are, case-insensitively:
+///
+/// - `now`
+/// - `today` or `tomorrow`, meaning the current time on that day
+/// - `today TIME` or `tomorrow TIME`, where `TIME` is `9am`, `9:30pm` or `14:00`
+/// - `in N minutes`, `in N hours` or `in N days`, the units also being singular or `min`/`mins`
+///
+/// Anything starting with one of those words is taken as a phrase, so that a mistyped one is
+/// reported as such rather than as not matching `--at-format`.
+fn parse_phrase(at: &str, now: OffsetDateTime) -> Option<Result<OffsetDateTime, Error>> {
+    let lowercase = at.to_lowercase();
+    let words: Vec<&str> = lowercase.split_whitespace().collect();
+    let invalid = || Error::Phrase(at.to_owned());
+    let day = match *words.first()? {
+        "now" if words.len() == 1 => return Some(Ok(now)),
+        "today" => now,
+        "tomorrow" => now + Duration::from_secs(24 * 60 * 60),
+        "in" => {
+            return Some(match words[1..] {
+                [count, unit] => count
+                    .parse::<u64>()
+                    .ok()
+                    .zip(unit_seconds(unit))
+                    .and_then(|(count, seconds)| ahead(now, count.checked_mul(seconds)?))
+                    .ok_or_else(invalid),
+                _ => Err(invalid()),
+            })
+        }
+        _ => return None,
+    };
+    Some(match words[1..] {
+        [] => Ok(day),
+        [time] => parse_time_of_day(time)
+            .map(|time| day.date().with_time(time).assume_offset(now.offset()))
+            .ok_or_else(invalid),
+        _ => Err(invalid()),
+    })
+}
+
+/// `now` moved `seconds` ahead, or `None` if that goes past the last date that can be written
+/// with four digits.
+fn ahead(now: OffsetDateTime, seconds: u64) -> Option<OffsetDateTime> {
+    let last = Date::try_from_ymd(9999, 12, 31)
+        .ok()?
+        .midnight()
+        .assume_offset(now.offset());
+    let ahead = time::Duration::seconds(i64::try_from(seconds).ok()?);
+    if ahead > last - now {
+        None
+    } else {
+        Some(now + ahead)
+    }
+}
+
+fn unit_seconds(unit: &str) -> Option<u64> {
+    match unit {
+        "minute" | "minutes" | "min" | "mins" => Some(60),
+        "hour" | "hours" => Some(60 * 60),
+        "day" | "days" => Some(24 * 60 * 60),
+        _ => None,
+    }
+}
+
+/// Parses a time of day written as `9am`, `12:30pm` or `14:00`.
+fn parse_time_of_day(time: &str) -> Option<Time> {
+    let (clock, pm) = match time.strip_suffix("am") {
+        Some(clock) => (clock, Some(false)),
+        None => match time.strip_suffix("pm") {
+            Some(clock) => (clock, Some(true)),
+            None => (time, None),
+        },
+    };
+    let (hour, minute) = match clock.split_once(':') {
+        Some((hour, minute)) if minute.len() == 2 => {
+            (hour.parse::<u8>().ok()?, minute.parse().ok()?)
+        }
+        Some(_) => return None,
+        // A bare number is only a time with am or pm after it.
+        None if pm.is_some() => (clock.parse::<u8>().ok()?, 0),
+        None => return None,
+    };
+    let hour = match pm {
+        Some(_) if !(1..=12).contains(&hour) => return None,
+        Some(false) => hour % 12,
+        Some(true) => hour % 12 + 12,
+        None => hour,
+    };
+    Time::try_from_hms(hour, minute, 0).ok()
+}
+
+/// Whether `at` looks like an ISO 8601 datetime, i.e. a full date joined to a time by a `T`.
+fn is_iso(at: &str) -> bool {
+    let bytes = at.as_bytes();
+    bytes.len() > 11
+        && bytes[10] == b'T'
+        && bytes[..10].iter().enumerate().all(|(i, b)| {
+            if i == 4 || i == 7 {
+                *b == b'-'
+            } else {
+                b.is_ascii_digit()
+            }
+        })
+}
+
+fn parse_iso(at: &str, offset: UtcOffset) -> Result<OffsetDateTime, Error> {
+    let time_part = &at[11..];
+    if time_part.ends_with('Z') || time_part.contains(['+', '-']) {
+        OffsetDateTime::parse(at, Format::Rfc3339)
+            .map(|dt| dt.to_offset(offset))
+            .map_err(|source| Error::Datetime {
+                input: at.to_owned(),
+                format: "RFC 3339".to_owned(),
+                source,
+            })
+    } else {
+        PrimitiveDateTime::parse(at, ISO_LOCAL_FORMAT)
+            .or_else(|_| PrimitiveDateTime::parse(at, ISO_LOCAL_FORMAT_NO_SECONDS))
+            .map(|dt| dt.assume_offset(offset))
+            .map_err(|source| Error::Datetime {
+                input: at.to_owned(),
+                format: ISO_LOCAL_FORMAT.to_owned(),
+                source,
+            })
+    }
+}
+
+/// The time events are looked for at, from `AT`, or between, from `--range`.
+#[derive(Debug, Clone, Copy)]
+pub enum Window {
+    At(OffsetDateTime),
+    Range(OffsetDateTime, OffsetDateTime),
+}
+
+impl Window {
+    /// Whether an event from `start` to `end` is in the window, which is what `khal at` and
+    /// `khal list` decide for events they read themselves.
+    pub fn overlaps(self, start: OffsetDateTime, end: OffsetDateTime) -> bool {
+        match self {
+            Window::At(at) => start <= at && at <= end,
+            Window::Range(from, to) => start <= to && from <= end,
+        }
+    }
+
+    /// The window in RFC 3339, with a range as `START/END`.
+    pub fn rfc3339(self) -> String {
+        match self {
+            Window::At(at) => at.format(Format::Rfc3339),
+            Window::Range(from, to) => {
+                format!(
+                    "{}/{}",
+                    from.format(Format::Rfc3339),
+                    to.format(Format::Rfc3339)
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{date, offset, time};
+
+    fn now() -> OffsetDateTime {
+        date!(2024 - 01 - 02)
+            .with_time(time!(10:15))
+            .assume_offset(offset!(+2))
+    }
+
+    fn phrase(at: &str) -> OffsetDateTime {
+        parse_phrase(at, now()).unwrap().unwrap()
+    }
+
+    fn on(day: Date, time: Time) -> OffsetDateTime {
+        day.with_time(time).assume_offset(offset!(+2))
+    }
+
+    #[test]
+    fn now_and_days() {
+        assert_eq!(phrase("now"), now());
+        assert_eq!(phrase("Today"), now());
+        assert_eq!(phrase("tomorrow"), on(date!(2024 - 01 - 03), time!(10:15)));
+    }
+
+    #[test]
+    fn days_with_times() {
+        assert_eq!(
+            phrase("tomorrow 9am"),
+            on(date!(2024 - 01 - 03), time!(9:00))
+        );
+        assert_eq!(
+            phrase("today 9:30pm"),
+            on(date!(2024 - 01 - 02), time!(21:30))
+        );
+        assert_eq!(
+            phrase("today 14:00"),
+            on(date!(2024 - 01 - 02), time!(14:00))
+        );
+        assert_eq!(phrase("today 12am"), on(date!(2024 - 01 - 02), time!(0:00)));
+        assert_eq!(
+            phrase("today 12pm"),
+            on(date!(2024 - 01 - 02), time!(12:00))
+        );
+    }
+
+    #[test]
+    fn relative_amounts() {
+        assert_eq!(
+            phrase("in 5 minutes"),
+            on(date!(2024 - 01 - 02), time!(10:20))
+        );
+        assert_eq!(phrase("in 1 min"), on(date!(2024 - 01 - 02), time!(10:16)));
+        assert_eq!(
+            phrase("in 2 hours"),
+            on(date!(2024 - 01 - 02), time!(12:15))
+        );
+        assert_eq!(phrase("in 3 days"), on(date!(2024 - 01 - 05), time!(10:15)));
+    }
+
+    #[test]
+    fn rejects_mistyped_phrases() {
+        for at in &[
+            "tomorrow 13pm",
+            "today 0am",
+            "today 9",
+            "today 9:3pm",
+            "today 25:00",
+            "tomorrow at 9am",
+            "in two hours",
+            "in 2 weeks",
+            "in 2",
+            "in 9999999999 days",
+            "in 18446744073709551615 minutes",
+        ] {
+            assert!(
+                matches!(parse_phrase(at, now()), Some(Err(Error::Phrase(_)))),
+                "{:?} was accepted",
+                at
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_other_input_alone() {
+        assert!(parse_phrase("2024-01-02 09:00", now()).is_none());
+        assert!(parse_phrase("30", now()).is_none());
+    }
+
+    #[test]
+    fn iso_with_offset_is_converted() {
+        let target =
+            parse_target("2024-01-02T09:00:00+09:00", DEFAULT_FORMAT, offset!(+2)).unwrap();
+        assert_eq!(target, on(date!(2024 - 01 - 02), time!(2:00)));
+        assert_eq!(target.offset(), offset!(+2));
+        let utc = parse_target("2024-01-02T09:00:00Z", DEFAULT_FORMAT, offset!(+2)).unwrap();
+        assert_eq!(utc, on(date!(2024 - 01 - 02), time!(11:00)));
+    }
+
+    #[test]
+    fn iso_without_offset_is_local() {
+        for at in &["2024-01-02T09:00", "2024-01-02T09:00:00"] {
+            let target = parse_target(at, DEFAULT_FORMAT, offset!(+2)).unwrap();
+            assert_eq!(target, on(date!(2024 - 01 - 02), time!(9:00)));
+            assert_eq!(target.offset(), offset!(+2));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_iso() {
+        assert!(parse_iso("2024-01-02T25:00:00+09:00", offset!(+2)).is_err());
+        assert!(parse_iso("2024-01-02T09", offset!(+2)).is_err());
+    }
+}
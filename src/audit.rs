@@ -0,0 +1,52 @@
+use crate::khal::KhalEvent;
+use crate::render::Notification;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use time::{Format, OffsetDateTime};
+
+/// A history of sent notifications, appended to as JSON lines by `--audit-log`.
+pub struct AuditLog {
+    path: String,
+    /// Held while a whole line is written, as notifications are sent from several threads.
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    title: &'a str,
+    body: &'a str,
+    calendar: &'a str,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> Result<AuditLog, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("could not open audit log {}: {}", path, err))?;
+        Ok(AuditLog {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, event: &KhalEvent, notification: &Notification) -> Result<(), String> {
+        let entry = Entry {
+            timestamp: OffsetDateTime::now_utc().format(Format::Rfc3339),
+            title: &notification.title,
+            body: &notification.body,
+            calendar: &event.calendar,
+        };
+        let mut line = serde_json::to_string(&entry).map_err(|err| err.to_string())?;
+        line.push('\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .map_err(|err| format!("could not write to audit log {}: {}", self.path, err))
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{desc, ics, khal, tz, which};
+use clap::ArgMatches;
+use regex::Regex;
+use std::env;
+use std::fs::File;
+use std::process::Command;
+
+/// Checks that everything a run needs is in place, printing a line for each check.
+///
+/// Returns whether they all passed.
+pub fn run(matches: &ArgMatches) -> bool {
+    let mut checks: Vec<(&str, Result<String, String>)> = Vec::new();
+    match matches.value_of("ics") {
+        Some(path) => checks.push(("ics file", check_ics(path))),
+        None => {
+            checks.push(("khal", check_khal(matches.value_of("khal path").unwrap())));
+            let config = matches.value_of("config").unwrap();
+            checks.push((
+                "khal config",
+                File::open(config)
+                    .map(|_| config.to_owned())
+                    .map_err(|err| format!("could not read {}: {}", config, err)),
+            ));
+        }
+    }
+    checks.push(("notify command", check_notify(matches)));
+    checks.push(("timezone", check_timezone(matches)));
+    checks.push(("strip regexes", check_strip_regexes(matches)));
+
+    let mut passed = true;
+    for (name, result) in checks {
+        match result {
+            Ok(detail) => println!("ok    {}: {}", name, detail),
+            Err(err) => {
+                println!("FAIL  {}: {}", name, err);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+fn check_khal(program: &str) -> Result<String, String> {
+    let khal = khal::resolve_program(program)?;
+    let output = Command::new(&khal)
+        .arg("--version")
+        .output()
+        .map_err(|err| format!("could not execute {}: {}", khal.display(), err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} --version failed: {}",
+            khal.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.lines().next().unwrap_or_default().trim();
+    Ok(format!("{} ({})", khal.display(), version))
+}
+
+fn check_ics(path: &str) -> Result<String, String> {
+    // The formats and offset don't matter for whether the file parses.
+    let events = ics::read_events(path, "%F", "%R", time::UtcOffset::UTC)?;
+    Ok(format!("{} ({} events)", path, events.len()))
+}
+
+fn check_notify(matches: &ArgMatches) -> Result<String, String> {
+    let command = matches.value_of("notify cmd").unwrap();
+    match matches.value_of("notify host") {
+        // The command itself is on the other machine, and only found when connecting.
+        Some(host) => {
+            which::find("ssh").map(|ssh| format!("{} on {} over {}", command, host, ssh.display()))
+        }
+        None => {
+            let mut found = vec![which::find(command)?.display().to_string()];
+            if let Some(urgent) = matches.value_of("urgent notify cmd") {
+                found.push(which::find(urgent)?.display().to_string());
+            }
+            Ok(found.join(", "))
+        }
+    }
+}
+
+fn check_timezone(matches: &ArgMatches) -> Result<String, String> {
+    if let Some(hours) = matches.value_of("utc offset") {
+        return hours
+            .parse::<i8>()
+            .map(|_| format!("{} hours, from --timezone", hours))
+            .map_err(|err| format!("--timezone {:?} is not a number of hours: {}", hours, err));
+    }
+    let source = match env::var("TZ") {
+        Ok(tz) => format!("from TZ={}", tz),
+        Err(_) => "from the system timezone".to_owned(),
+    };
+    tz::local_offset()
+        .map(|offset| format!("{} {}", offset.format("%z"), source))
+        .ok_or_else(|| "could not be found, pass --timezone".to_owned())
+}
+
+fn check_strip_regexes(matches: &ArgMatches) -> Result<String, String> {
+    let presets = desc::strip_presets(
+        matches
+            .values_of("strip preset")
+            .into_iter()
+            .flatten()
+            .flat_map(|presets| presets.split(',')),
+    )?;
+    let mut count = presets.len();
+    let mut errors = Vec::new();
+    if let Some(path) = matches.value_of("strip regex file") {
+        match desc::read_strip_file(path) {
+            Ok(regexes) => count += regexes.len(),
+            Err(err) => errors.push(err),
+        }
+    }
+    for pattern in matches.values_of("strip regex").into_iter().flatten() {
+        match Regex::new(pattern) {
+            Ok(_) => count += 1,
+            Err(err) => errors.push(format!("{:?}: {}", pattern, err)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(format!("{} compiled", count))
+    } else {
+        Err(errors.join("\n      "))
+    }
+}
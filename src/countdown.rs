@@ -0,0 +1,67 @@
+use crate::khal::KhalEvent;
+use crate::locale::{Locale, Phrase};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+use time::{NumericalDuration, OffsetDateTime};
+
+/// Prints how long is left until the first of `events` to start, other than all-day ones and
+/// those that already started, updating it each second until the event starts.
+///
+/// With `rewrite` each update replaces the last on the same line, as a terminal or tmux shows it,
+/// otherwise each goes on a line of its own for status bars that read lines.
+pub fn run(events: &[KhalEvent], locale: Locale, rewrite: bool) -> Result<(), String> {
+    let next = events
+        .iter()
+        .filter(|event| !event.all_day && !event.started)
+        .filter_map(|event| event.start.map(|start| (start, event)))
+        .min_by_key(|(start, _)| *start);
+    let (start, event) = match next {
+        Some(next) => next,
+        None => {
+            eprintln!("note: no upcoming event to count down to");
+            return Ok(());
+        }
+    };
+    let title = event.title.replace('\n', " ");
+    let mut stdout = io::stdout();
+    loop {
+        let left = start - OffsetDateTime::now_utc();
+        if !left.is_positive() {
+            break;
+        }
+        // Rounded up, so that 0:00:00 is never shown before the event starts.
+        let seconds = left.whole_seconds() + i64::from(left.subsec_nanoseconds() > 0);
+        let line = locale
+            .text(Phrase::Until)
+            .replace("{time}", &clock(seconds))
+            .replace("{title}", &title);
+        let written = if rewrite {
+            // Clears what's left of a longer line before it.
+            write!(stdout, "\r{}\x1b[K", line)
+        } else {
+            writeln!(stdout, "{}", line)
+        };
+        written
+            .and_then(|()| stdout.flush())
+            .map_err(|err| format!("could not write countdown: {}", err))?;
+        // Until the number of seconds shown goes down.
+        let wait = left - (seconds - 1).seconds();
+        thread::sleep(Duration::try_from(wait).unwrap_or_default());
+    }
+    if rewrite {
+        println!();
+    }
+    Ok(())
+}
+
+/// Formats `seconds` as hours, minutes and seconds, like 0:09:32.
+fn clock(seconds: i64) -> String {
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,754 @@
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Marks where text was truncated.
+pub const ELLIPSIS: &str = "...";
+
+/// The regex crate already matches in linear time, but the bounded repetitions in the URL pattern
+/// compile to a large program, so it's kept from growing its matching caches without limit on
+/// long descriptions.
+static URL_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new(URL_PATTERN)
+        .size_limit(URL_REGEX_SIZE_LIMIT)
+        .dfa_size_limit(URL_REGEX_DFA_SIZE_LIMIT)
+        .build()
+        .unwrap()
+});
+const URL_REGEX_SIZE_LIMIT: usize = 1 << 20;
+const URL_REGEX_DFA_SIZE_LIMIT: usize = 1 << 20;
+const URL_PATTERN: &str = r"(https?://(www\.)?)?[-a-zA-Z0-9@:%._\+~#=]{1,256}\.[a-zA-Z0-9()]{1,6}\b([-a-zA-Z0-9()@:%_\+.~#?&//=]*)";
+
+static MARKDOWN_LINK_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\[([^\[\]\n]+)\](?:\(<?([^()<>\s]+)>?(?:\s+"[^"\n]*")?\)|\[([^\[\]\n]*)\])?"#)
+        .unwrap()
+});
+static MARKDOWN_DEFINITION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?m)^[ \t]{0,3}\[([^\[\]\n]+)\]:[ \t]*<?([^\s<>]+)>?(?:[ \t]+(?:"[^"\n]*"|'[^'\n]*'|\([^)\n]*\)))?[ \t]*(?:\n|$)"#).unwrap()
+});
+
+/// Curated strip regexes for common boilerplate, selectable by name with `--strip-preset`.
+pub const STRIP_PRESETS: [(&str, &[&str]); 3] = [
+    (
+        "zoom",
+        &[
+            r"(?m)^[ \t]*(Meeting ID|Passcode|Password|One tap mobile|Dial by your location|Find your local number|Join by SIP|Join by H\.323)\b.*$\n?",
+            r"(?m)^[ \t]*\+\d[\d ,#*]+.*$\n?",
+        ],
+    ),
+    (
+        "meet",
+        &[r"(?s)-::~[:~]*::-.*?-::~[:~]*::-\n?", r"(?s)-::~[:~]*::-.*"],
+    ),
+    ("signature", &[r"(?ms)^-- $.*"]),
+];
+
+/// Compiles the regexes of the named presets, in the order they're given.
+pub fn strip_presets<'a>(
+    names: impl Iterator<Item = &'a str>,
+) -> Result<Vec<(Regex, String)>, String> {
+    let mut regexes = Vec::new();
+    for name in names {
+        let (_, patterns) = STRIP_PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .ok_or_else(|| format!("unknown strip preset {:?}", name))?;
+        regexes.extend(
+            patterns
+                .iter()
+                .map(|pattern| (Regex::new(pattern).unwrap(), String::new())),
+        );
+    }
+    Ok(regexes)
+}
+
+/// Reads strip regexes from the file at `path`, one per line in the order they're listed.
+///
+/// A line can be `PATTERN=REPLACEMENT` to replace matches rather than remove them, with `$1` and
+/// the like for groups, so an `=` in the pattern itself is written `\=`. Blank lines are skipped,
+/// as are lines starting with `#`.
+pub fn read_strip_file(path: &str) -> Result<Vec<(Regex, String)>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("could not read strip regex file {}: {}", path, err))?;
+    let mut regexes = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let split = line
+            .match_indices('=')
+            .map(|(i, _)| i)
+            .find(|&i| !line[..i].ends_with('\\'));
+        let (pattern, replacement) = match split {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        let pattern = pattern.replace("\\=", "=");
+        let regex = Regex::new(&pattern)
+            .map_err(|err| format!("{}:{}: {:?}: {}", path, number + 1, pattern, err))?;
+        regexes.push((regex, replacement.to_owned()));
+    }
+    Ok(regexes)
+}
+
+/// The markup language notifications are written in, which decides how links are shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Markup {
+    /// Pango anchors, as understood by notify-send.py.
+    Pango,
+    Plain,
+    Markdown,
+}
+
+impl Markup {
+    /// Formats the links found in a truncated description, to go after the ellipsis with
+    /// `separator` between them.
+    pub fn links(self, urls: &[&str], separator: &str) -> String {
+        if urls.is_empty() {
+            return String::new();
+        }
+        let links: Vec<String> = match self {
+            Markup::Pango => urls
+                .iter()
+                .map(|url| format!("<a href=\"{}\"></a>", url))
+                .collect(),
+            Markup::Plain => urls.iter().map(|url| url.to_string()).collect(),
+            Markup::Markdown => urls
+                .iter()
+                .map(|url| format!("[{}]({})", host(url), url))
+                .collect(),
+        };
+        let lead = if self == Markup::Pango { "" } else { " " };
+        format!("{}{}", lead, links.join(separator))
+    }
+}
+
+impl FromStr for Markup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pango" => Ok(Markup::Pango),
+            "plain" => Ok(Markup::Plain),
+            "markdown" => Ok(Markup::Markdown),
+            _ => Err(format!("unknown markup {:?}", s)),
+        }
+    }
+}
+
+/// The host part of `url`, used as the text of markdown links.
+pub fn host(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let host = without_scheme
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or(without_scheme);
+    host.strip_prefix("www.").unwrap_or(host)
+}
+
+/// What's done with URLs in the visible part of a description, from `--link-mode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkMode {
+    /// Leave them in the text, only listing the links from the truncated part.
+    Append,
+    /// Take them out of the text and list them with the others.
+    Replace,
+    /// Make them into links where they are, as far as the markup allows.
+    Inline,
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(LinkMode::Append),
+            "replace" => Ok(LinkMode::Replace),
+            "inline" => Ok(LinkMode::Inline),
+            _ => Err(format!("unknown link mode {:?}", s)),
+        }
+    }
+}
+
+/// Whether strip regexes run on the whole description or only on what's left after truncating.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StripOrder {
+    Before,
+    After,
+}
+
+impl FromStr for StripOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "before" => Ok(StripOrder::Before),
+            "after" => Ok(StripOrder::After),
+            _ => Err(format!("unknown strip order {:?}", s)),
+        }
+    }
+}
+
+/// Unicode normalization form applied to event text before it's measured or truncated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+    None,
+}
+
+impl Normalization {
+    pub fn apply(self, text: &str) -> String {
+        match self {
+            Normalization::Nfc => text.nfc().collect(),
+            Normalization::Nfd => text.nfd().collect(),
+            Normalization::None => text.to_owned(),
+        }
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            "none" => Ok(Normalization::None),
+            _ => Err(format!("unknown normalization {:?}", s)),
+        }
+    }
+}
+
+/// A description cut down by [`Options::shorten`].
+pub struct Shortened {
+    pub text: String,
+    /// Links from the truncated part, formatted to follow the text.
+    pub links: String,
+}
+
+/// How event descriptions are cut down to fit in a notification.
+pub struct Options {
+    /// Regexes for text to strip, each with what to replace its matches with.
+    pub strip_regexes: Vec<(Regex, String)>,
+    pub strip_order: StripOrder,
+    /// Descriptions longer than this many graphemes are truncated, or never if `None`.
+    pub max_chars: Option<usize>,
+    /// Descriptions shorter than this many graphemes once stripped are left out altogether.
+    pub min_chars: usize,
+    /// Append links from the truncated part, which is the only time URLs are looked for at all.
+    pub links: bool,
+    /// List links alphabetically rather than in the order they appear.
+    pub sort_links: bool,
+    /// Put between links, but not after the last one.
+    pub link_separator: String,
+    /// What's done with bare URLs in the text that's shown.
+    pub link_mode: LinkMode,
+    /// Replace Markdown links with their text, keeping their URLs as links.
+    pub markdown_links: bool,
+    /// Collapse runs of whitespace before truncating, see [`flatten_whitespace`].
+    pub flatten_whitespace: bool,
+    pub markup: Markup,
+}
+
+impl Options {
+    /// Strips and truncates `description`, collecting links from the truncated part.
+    ///
+    /// Links are looked for in the same text that was truncated, so stripping before truncation
+    /// also removes any links in the stripped text, while stripping after doesn't.
+    pub fn shorten(&self, description: &str) -> Shortened {
+        self.shorten_with(description, true)
+    }
+
+    /// Like [`Options::shorten`], for a description that was already stripped, which stripping
+    /// again would change when a regex has a replacement.
+    pub fn shorten_stripped(&self, description: &str) -> Shortened {
+        self.shorten_with(description, false)
+    }
+
+    fn shorten_with(&self, description: &str, strip: bool) -> Shortened {
+        let strip = |text: &str| {
+            if strip {
+                self.strip(text)
+            } else {
+                text.to_owned()
+            }
+        };
+        let text = match self.strip_order {
+            StripOrder::Before => strip(description),
+            StripOrder::After => description.to_owned(),
+        };
+        let text = if self.flatten_whitespace {
+            flatten_whitespace(&text)
+        } else {
+            text
+        };
+        if self.min_chars > 0 {
+            let stripped = match self.strip_order {
+                StripOrder::Before => text.clone(),
+                StripOrder::After => strip(&text),
+            };
+            if stripped.trim().graphemes(true).count() < self.min_chars {
+                return Shortened {
+                    text: String::new(),
+                    links: String::new(),
+                };
+            }
+        }
+        let (text, markdown_links) = if self.markdown_links {
+            parse_markdown_links(&text)
+        } else {
+            (text, Vec::new())
+        };
+        let cut = self.truncation_point(&text, &markdown_links);
+        let (visible, rest) = text.split_at(cut.unwrap_or(text.len()));
+        let (shown, hidden): (Vec<&TextLink>, Vec<&TextLink>) = markdown_links
+            .iter()
+            .partition(|link| link.end <= visible.len());
+
+        // In the default mode bare URLs in the visible text are left alone.
+        let bare_urls: Vec<TextLink> = if self.links && self.link_mode != LinkMode::Append {
+            URL_REGEX
+                .find_iter(visible)
+                .filter(|url| {
+                    !shown
+                        .iter()
+                        .any(|link| link.start < url.end() && url.start() < link.end)
+                })
+                .map(|url| TextLink {
+                    start: url.start(),
+                    end: url.end(),
+                    url: url.as_str().to_owned(),
+                    bare: true,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut in_text: Vec<&TextLink> = shown.iter().copied().chain(&bare_urls).collect();
+        in_text.sort_by_key(|link| link.start);
+
+        let visible = self.inline_links(visible, &in_text);
+        let mut shortened = match self.strip_order {
+            StripOrder::Before => visible,
+            StripOrder::After => strip(&visible),
+        };
+        if cut.is_some() {
+            shortened += ELLIPSIS;
+        }
+        let mut urls: Vec<&str> = Vec::new();
+        if self.links {
+            // Plain text has nowhere to keep the URLs of the links it shows.
+            if self.markup == Markup::Plain {
+                urls.extend(shown.iter().map(|link| link.url.as_str()));
+            }
+            if self.link_mode == LinkMode::Replace {
+                urls.extend(bare_urls.iter().map(|link| link.url.as_str()));
+            }
+            urls.extend(hidden.iter().map(|link| link.url.as_str()));
+            if cut.is_some() {
+                urls.extend(find_links(rest, false));
+            }
+            // URLs already made into anchors in the text aren't listed again.
+            let mut seen: Vec<&str> = if self.link_mode == LinkMode::Inline {
+                bare_urls.iter().map(|link| link.url.as_str()).collect()
+            } else {
+                Vec::new()
+            };
+            urls.retain(|url| {
+                let new = !seen.contains(url);
+                seen.push(*url);
+                new
+            });
+            if self.sort_links {
+                urls.sort_unstable();
+            }
+        }
+        Shortened {
+            text: shortened,
+            links: self.markup.links(&urls, &self.link_separator),
+        }
+    }
+
+    /// Puts the URLs of the links in `text` back, in whatever way the markup can, and handles bare
+    /// URLs as `link_mode` says.
+    fn inline_links(&self, text: &str, links: &[&TextLink]) -> String {
+        let mut inlined = String::with_capacity(text.len());
+        let mut last = 0;
+        for link in links {
+            inlined.push_str(&text[last..link.start]);
+            last = link.end;
+            let label = &text[link.start..link.end];
+            if link.bare && self.link_mode == LinkMode::Replace {
+                // Don't leave two spaces where the URL was.
+                if inlined.ends_with(' ') && text[last..].starts_with(' ') {
+                    last += 1;
+                }
+                continue;
+            }
+            match self.markup {
+                Markup::Pango => inlined += &format!("<a href=\"{}\">{}</a>", link.url, label),
+                Markup::Markdown if link.bare => {
+                    inlined += &format!("[{}]({})", host(&link.url), link.url)
+                }
+                Markup::Markdown => inlined += &format!("[{}]({})", label, link.url),
+                Markup::Plain => inlined.push_str(label),
+            }
+        }
+        inlined.push_str(&text[last..]);
+        inlined
+    }
+
+    /// Finds the byte offset to truncate `text` at, if it's longer than `max_chars` graphemes.
+    ///
+    /// Unless links are disabled, a cut that would land inside a URL is moved to before it, so
+    /// that the URL is listed whole with the other links instead of appearing broken in the
+    /// visible text. The same goes for the text of Markdown links.
+    fn truncation_point(&self, text: &str, markdown_links: &[TextLink]) -> Option<usize> {
+        let cut = text
+            .grapheme_indices(true)
+            .nth(self.max_chars?)
+            .map(|(i, _)| i)?;
+        if !self.links {
+            return Some(cut);
+        }
+        let cut = URL_REGEX
+            .find_iter(text)
+            .find(|url| url.start() < cut && cut < url.end())
+            .map_or(cut, |url| url.start());
+        Some(
+            markdown_links
+                .iter()
+                .find(|link| link.start < cut && cut < link.end)
+                .map_or(cut, |link| link.start),
+        )
+    }
+
+    pub fn strip(&self, text: &str) -> String {
+        self.strip_regexes
+            .iter()
+            .fold(text.to_owned(), |d, (regex, replacement)| {
+                regex.replace_all(&d, replacement.as_str()).into_owned()
+            })
+    }
+}
+
+/// A link whose text is at `start..end` in a description, which for a bare URL is the URL itself.
+struct TextLink {
+    start: usize,
+    end: usize,
+    url: String,
+    bare: bool,
+}
+
+/// Replaces inline (`[text](url)`) and reference-style (`[text][ref]`, `[text][]` and `[text]`)
+/// Markdown links in `text` with their text, and drops the definitions of references.
+///
+/// References that aren't defined are left as they are.
+fn parse_markdown_links(text: &str) -> (String, Vec<TextLink>) {
+    let mut definitions: HashMap<String, String> = HashMap::new();
+    for caps in MARKDOWN_DEFINITION_REGEX.captures_iter(text) {
+        definitions
+            .entry(caps[1].to_lowercase())
+            .or_insert_with(|| caps[2].to_owned());
+    }
+    let mut text = MARKDOWN_DEFINITION_REGEX.replace_all(text, "");
+    // Definitions usually come last, after a blank line that'd otherwise be left over.
+    if !definitions.is_empty() {
+        let end = text.trim_end().len();
+        text.to_mut().truncate(end);
+    }
+
+    let mut parsed = String::with_capacity(text.len());
+    let mut links = Vec::new();
+    let mut last = 0;
+    for caps in MARKDOWN_LINK_REGEX.captures_iter(&text) {
+        let whole = caps.get(0).unwrap();
+        let label = &caps[1];
+        let url = match (caps.get(2), caps.get(3)) {
+            (Some(url), _) => Some(url.as_str().to_owned()),
+            (None, reference) => {
+                let reference = reference.map_or("", |r| r.as_str());
+                let reference = if reference.is_empty() {
+                    label
+                } else {
+                    reference
+                };
+                definitions.get(&reference.to_lowercase()).cloned()
+            }
+        };
+        let url = match url {
+            Some(url) => url,
+            None => continue,
+        };
+        parsed.push_str(&text[last..whole.start()]);
+        let start = parsed.len();
+        parsed.push_str(label);
+        links.push(TextLink {
+            start,
+            end: parsed.len(),
+            url,
+            bare: false,
+        });
+        last = whole.end();
+    }
+    parsed.push_str(&text[last..]);
+    (parsed, links)
+}
+
+/// Turns each run of whitespace in `text` into a single newline if it has a line break in it, or
+/// a single space if not, and trims it, so that `\r\n` line endings and blank lines don't take up
+/// room in the notification.
+pub fn flatten_whitespace(text: &str) -> String {
+    let mut flattened = String::with_capacity(text.len());
+    let mut run: Option<bool> = None;
+    for c in text.trim().chars() {
+        if c.is_whitespace() {
+            let line_break = c == '\n' || c == '\r';
+            run = Some(run.unwrap_or_default() || line_break);
+            continue;
+        }
+        match run.take() {
+            Some(true) => flattened.push('\n'),
+            Some(false) => flattened.push(' '),
+            None => {}
+        }
+        flattened.push(c);
+    }
+    flattened
+}
+
+/// Pulls the distinct URLs out of `rem_desc`, in order of appearance unless `sort`.
+pub fn find_links(rem_desc: &str, sort: bool) -> Vec<&str> {
+    let mut url_matches: Vec<&str> = Vec::new();
+    for url in URL_REGEX.find_iter(rem_desc).map(|url| url.as_str()) {
+        if !url_matches.contains(&url) {
+            url_matches.push(url);
+        }
+    }
+    if sort {
+        url_matches.sort_unstable();
+    }
+    url_matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn options() -> Options {
+        Options {
+            strip_regexes: Vec::new(),
+            strip_order: StripOrder::Before,
+            max_chars: None,
+            min_chars: 0,
+            links: true,
+            sort_links: false,
+            link_separator: " ".to_owned(),
+            link_mode: LinkMode::Append,
+            markdown_links: false,
+            flatten_whitespace: false,
+            markup: Markup::Plain,
+        }
+    }
+
+    #[test]
+    fn cut_inside_url_backs_up_to_its_start() {
+        let options = Options {
+            max_chars: Some(10),
+            ..options()
+        };
+        let text = "See https://example.com/meeting now";
+        assert_eq!(options.truncation_point(text, &[]), Some(4));
+        let shortened = options.shorten(text);
+        assert_eq!(shortened.text, "See ...");
+        assert_eq!(shortened.links, " https://example.com/meeting");
+    }
+
+    #[test]
+    fn cut_inside_url_stays_without_links() {
+        let options = Options {
+            max_chars: Some(10),
+            links: false,
+            ..options()
+        };
+        let text = "See https://example.com/meeting now";
+        assert_eq!(options.truncation_point(text, &[]), Some(10));
+    }
+
+    #[test]
+    fn long_repetitive_text_returns_quickly() {
+        let text = "a.".repeat(512 * 1024);
+        let started = Instant::now();
+        find_links(&text, false);
+        let elapsed = started.elapsed();
+        assert!(elapsed < Duration::from_secs(5), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn no_max_keeps_long_description_whole() {
+        let description = "abcdefghij ".repeat(45) + "https://example.com/end";
+        assert!(description.len() > 500);
+        let options = Options {
+            max_chars: None,
+            ..options()
+        };
+        let shortened = options.shorten(&description);
+        assert_eq!(shortened.text, description);
+        assert_eq!(shortened.links, "");
+    }
+
+    fn stripping_meeting_ids(strip_order: StripOrder) -> Options {
+        Options {
+            strip_regexes: vec![(Regex::new(r"Meeting ID: \d+").unwrap(), String::new())],
+            strip_order,
+            min_chars: 10,
+            ..options()
+        }
+    }
+
+    #[test]
+    fn stripped_below_min_length_is_left_out() {
+        for &strip_order in &[StripOrder::Before, StripOrder::After] {
+            let options = stripping_meeting_ids(strip_order);
+            let shortened = options.shorten("Meeting ID: 123456789 ok");
+            assert_eq!(shortened.text, "", "{:?}", strip_order);
+            assert_eq!(shortened.links, "");
+            let kept = options.shorten("Meeting ID: 123456789 bring slides");
+            assert_eq!(kept.text, " bring slides", "{:?}", strip_order);
+        }
+    }
+
+    fn markdown_links(text: &str) -> (String, Vec<(String, String)>) {
+        let (parsed, links) = parse_markdown_links(text);
+        let links = links
+            .iter()
+            .map(|link| (parsed[link.start..link.end].to_owned(), link.url.clone()))
+            .collect();
+        (parsed, links)
+    }
+
+    #[test]
+    fn inline_markdown_link() {
+        let (text, links) = markdown_links("Join [the call](https://example.com/call) now");
+        assert_eq!(text, "Join the call now");
+        assert_eq!(
+            links,
+            [("the call".to_owned(), "https://example.com/call".to_owned())]
+        );
+    }
+
+    #[test]
+    fn reference_markdown_links() {
+        let (text, links) = markdown_links(
+            "See [the agenda][agenda], [Notes][] and [docs].\n\n\
+             [agenda]: https://example.com/agenda\n\
+             [notes]: https://example.com/notes \"Notes\"\n\
+             [Docs]: <https://example.com/docs>",
+        );
+        assert_eq!(text, "See the agenda, Notes and docs.");
+        assert_eq!(
+            links,
+            [
+                (
+                    "the agenda".to_owned(),
+                    "https://example.com/agenda".to_owned()
+                ),
+                ("Notes".to_owned(), "https://example.com/notes".to_owned()),
+                ("docs".to_owned(), "https://example.com/docs".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn undefined_markdown_reference_is_left_alone() {
+        let (text, links) = markdown_links("See [the agenda][missing] and [this].");
+        assert_eq!(text, "See [the agenda][missing] and [this].");
+        assert!(links.is_empty());
+    }
+
+    #[test]
+    fn flattens_crlf_and_blank_lines() {
+        assert_eq!(
+            flatten_whitespace("Agenda:\r\n\r\n\r\n- intro\r\n- demo\r\n"),
+            "Agenda:\n- intro\n- demo"
+        );
+        assert_eq!(
+            flatten_whitespace("one\n\n\n\ntwo \t \n  three"),
+            "one\ntwo\nthree"
+        );
+    }
+
+    #[test]
+    fn flattens_spaces_within_lines() {
+        assert_eq!(flatten_whitespace("  a \t  b  "), "a b");
+        assert_eq!(flatten_whitespace("\r\n\r\n"), "");
+    }
+
+    fn with_link_mode(link_mode: LinkMode, markup: Markup) -> Shortened {
+        Options {
+            max_chars: Some(60),
+            link_mode,
+            markup,
+            ..options()
+        }
+        .shorten(
+            "Notes: https://a.example.com and https://a.example.com, \
+             see also https://b.example.com/x",
+        )
+    }
+
+    #[test]
+    fn append_link_mode_leaves_visible_urls() {
+        let shortened = with_link_mode(LinkMode::Append, Markup::Plain);
+        assert_eq!(
+            shortened.text,
+            "Notes: https://a.example.com and https://a.example.com, see ..."
+        );
+        assert_eq!(shortened.links, " https://b.example.com/x");
+    }
+
+    #[test]
+    fn replace_link_mode_lists_visible_urls_once() {
+        let shortened = with_link_mode(LinkMode::Replace, Markup::Plain);
+        assert_eq!(shortened.text, "Notes: and , see ...");
+        assert_eq!(
+            shortened.links,
+            " https://a.example.com https://b.example.com/x"
+        );
+        let pango = with_link_mode(LinkMode::Replace, Markup::Pango);
+        assert_eq!(
+            pango.links,
+            "<a href=\"https://a.example.com\"></a> <a href=\"https://b.example.com/x\"></a>"
+        );
+    }
+
+    #[test]
+    fn inline_link_mode_makes_anchors_in_place() {
+        let pango = with_link_mode(LinkMode::Inline, Markup::Pango);
+        assert_eq!(
+            pango.text,
+            "Notes: <a href=\"https://a.example.com\">https://a.example.com</a> and \
+             <a href=\"https://a.example.com\">https://a.example.com</a>, see ..."
+        );
+        assert_eq!(pango.links, "<a href=\"https://b.example.com/x\"></a>");
+        let markdown = with_link_mode(LinkMode::Inline, Markup::Markdown);
+        assert_eq!(
+            markdown.text,
+            "Notes: [a.example.com](https://a.example.com) and \
+             [a.example.com](https://a.example.com), see ..."
+        );
+        assert_eq!(markdown.links, " [b.example.com](https://b.example.com/x)");
+        let plain = with_link_mode(LinkMode::Inline, Markup::Plain);
+        assert_eq!(
+            plain.text,
+            "Notes: https://a.example.com and https://a.example.com, see ..."
+        );
+        assert_eq!(plain.links, " https://b.example.com/x");
+    }
+}
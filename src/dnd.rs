@@ -0,0 +1,13 @@
+use std::process::{Command, Stdio};
+
+/// Whether dunst has notifications paused, as reported by `dunstctl is-paused`.
+///
+/// Anything but a clear yes, including dunstctl not being installed, counts as not paused, so
+/// that notifications are sent rather than lost.
+pub fn is_paused() -> bool {
+    Command::new("dunstctl")
+        .arg("is-paused")
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| output.status.success() && output.stdout.trim_ascii() == b"true")
+}
@@ -0,0 +1,70 @@
+use crate::khal::KhalEvent;
+use crate::notify::Notifier;
+use crate::{desc, render, shell};
+
+/// Finds the one event whose title contains `title`, or the first of them if `first`.
+pub fn find<'a>(
+    events: &'a [KhalEvent],
+    title: &str,
+    first: bool,
+) -> Result<&'a KhalEvent, String> {
+    let matching: Vec<&KhalEvent> = events.iter().filter(|e| e.title.contains(title)).collect();
+    match matching[..] {
+        [] => Err(format!("no event has a title containing {:?}", title)),
+        [event] => Ok(event),
+        [event, ..] if first => Ok(event),
+        _ => {
+            let titles: Vec<String> = matching.iter().map(|e| format!("{:?}", e.title)).collect();
+            Err(format!(
+                "{} events have a title containing {:?}: {} (see --explain-first)",
+                matching.len(),
+                title,
+                titles.join(", ")
+            ))
+        }
+    }
+}
+
+/// Prints each step of turning `event` into a notification, ending with the notify command that
+/// would be run for it.
+pub fn print(
+    event: &KhalEvent,
+    render_options: &render::Options,
+    notifier: &Notifier,
+) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(event).map_err(|err| err.to_string())?;
+    println!("event:\n{}", json);
+    if let Some(description) = &render_options.description {
+        let stripped = description.strip(&event.description);
+        let links = desc::find_links(&stripped, description.sort_links);
+        println!("stripped description:\n{}", stripped);
+        println!(
+            "links: {}",
+            if links.is_empty() {
+                "none".to_owned()
+            } else {
+                links.join(" ")
+            }
+        );
+        let shortened = description.shorten(&event.description);
+        println!("truncated description:\n{}", shortened.text);
+        println!("appended links: {:?}", shortened.links);
+    }
+    let notification = render::render_event(event, render_options);
+    println!("title: {}", notification.title);
+    println!("body:\n{}", notification.body);
+    println!(
+        "urgency: {}",
+        notifier
+            .urgency(event)
+            .map_or("default", |urgency| urgency.name())
+    );
+    let command: String = notifier
+        .command_line(event, &notification.title, &notification.body)
+        .iter()
+        .map(|arg| shell::quote(arg))
+        .intersperse(" ".into())
+        .collect();
+    println!("command: {}", command);
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use crate::desc;
+use crate::khal::KhalEvent;
+use std::str::FromStr;
+
+/// The kinds of event `--auto-icon` tells apart, most specific first, along with the icon each
+/// gets by default.
+const DEFAULT_ICONS: [(Kind, &str); 3] = [
+    (Kind::Birthday, "emblem-favorite"),
+    (Kind::AllDay, "x-office-calendar"),
+    (Kind::Video, "camera-web"),
+];
+
+/// Icons for the meeting services `--icon-from-url-host` knows, by the host of their links, which
+/// also matches its subdomains.
+const DEFAULT_HOST_ICONS: [(&str, &str); 5] = [
+    ("zoom.us", "Zoom"),
+    ("meet.google.com", "google-meet"),
+    ("teams.microsoft.com", "teams"),
+    ("meet.jit.si", "jitsi-meet"),
+    ("webex.com", "webex"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Events with "birthday" in their title or calendar name.
+    Birthday,
+    AllDay,
+    /// Events with a link in their description, which is usually the one to join a call with.
+    Video,
+}
+
+impl Kind {
+    fn applies_to(self, event: &KhalEvent) -> bool {
+        match self {
+            Kind::Birthday => [&event.title, &event.calendar]
+                .iter()
+                .any(|text| text.to_lowercase().contains("birthday")),
+            Kind::AllDay => event.is_all_day(),
+            Kind::Video => !desc::find_links(&event.description, false).is_empty(),
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "birthday" => Ok(Kind::Birthday),
+            "all-day" => Ok(Kind::AllDay),
+            "video" => Ok(Kind::Video),
+            _ => Err(format!("unknown event kind {:?}", s)),
+        }
+    }
+}
+
+/// Icon names picked for events by the host of their links, or else by their kind.
+#[derive(Default)]
+pub struct Icons {
+    kinds: Vec<(Kind, String)>,
+    hosts: Vec<(String, String)>,
+}
+
+impl Icons {
+    /// The default icons, with `overrides` replacing some of them as comma-separated `KIND=ICON`
+    /// pairs, where an empty icon leaves that kind without one.
+    pub fn with_overrides(overrides: Option<&str>) -> Result<Icons, String> {
+        let mut icons: Vec<(Kind, String)> = DEFAULT_ICONS
+            .iter()
+            .map(|&(kind, icon)| (kind, icon.to_owned()))
+            .collect();
+        for pair in overrides.into_iter().flat_map(|o| o.split(',')) {
+            let (kind, icon) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected KIND=ICON, got {:?}", pair))?;
+            let kind: Kind = kind.parse()?;
+            for (_, default) in icons.iter_mut().filter(|(k, _)| *k == kind) {
+                *default = icon.to_owned();
+            }
+        }
+        Ok(Icons {
+            kinds: icons,
+            hosts: Vec::new(),
+        })
+    }
+
+    /// Also picks icons by the host of the event's links, from the built-in ones with `overrides`
+    /// as comma-separated `HOST=ICON` pairs, which can add hosts or take them away with an empty
+    /// icon.
+    pub fn with_host_icons(mut self, overrides: Option<&str>) -> Result<Icons, String> {
+        self.hosts = DEFAULT_HOST_ICONS
+            .iter()
+            .map(|&(host, icon)| (host.to_owned(), icon.to_owned()))
+            .collect();
+        for pair in overrides.into_iter().flat_map(|o| o.split(',')) {
+            let (host, icon) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected HOST=ICON, got {:?}", pair))?;
+            self.hosts.retain(|(known, _)| known != host);
+            if !icon.is_empty() {
+                self.hosts.push((host.to_owned(), icon.to_owned()));
+            }
+        }
+        Ok(self)
+    }
+
+    /// The icon for the first of `event`'s links with a known host, or else for the most
+    /// specific kind `event` is, if it has one.
+    pub fn for_event(&self, event: &KhalEvent) -> Option<&str> {
+        self.for_links(event).or_else(|| {
+            self.kinds
+                .iter()
+                .find(|(kind, _)| kind.applies_to(event))
+                .map(|(_, icon)| icon.as_str())
+                .filter(|icon| !icon.is_empty())
+        })
+    }
+
+    fn for_links(&self, event: &KhalEvent) -> Option<&str> {
+        if self.hosts.is_empty() {
+            return None;
+        }
+        desc::find_links(&event.description, false)
+            .into_iter()
+            .find_map(|link| {
+                let host = desc::host(link).to_lowercase();
+                self.hosts.iter().find(|(known, _)| {
+                    host == *known
+                        || host
+                            .strip_suffix(known.as_str())
+                            .is_some_and(|sub| sub.ends_with('.'))
+                })
+            })
+            .map(|(_, icon)| icon.as_str())
+    }
+}
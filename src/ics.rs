@@ -0,0 +1,260 @@
+use crate::khal::KhalEvent;
+use icalendar::{Calendar, CalendarDateTime, Component, DatePerhapsTime, EventLike};
+use indexmap::IndexMap;
+use std::fs;
+use std::path::Path;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+/// Shown in place of khal's repeat symbol for events with a recurrence rule.
+const REPEAT_SYMBOL: &str = "⟳";
+
+/// Reads the events in the iCalendar file at `path` into the same form khal lists them in.
+///
+/// Dates and times are written out in `date_format` and `time_format` like khal's, and `start`
+/// and `end` are filled in so that the events can be matched against the window before that.
+/// Floating times and times with a TZID are taken to be in `offset`, as timezone definitions
+/// aren't read, and recurring events only appear as their first occurrence. Both are warned
+/// about on stderr.
+pub fn read_events(
+    path: &str,
+    date_format: &str,
+    time_format: &str,
+    offset: UtcOffset,
+) -> Result<Vec<KhalEvent>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    let calendar_name = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (events, ignored) =
+        parse_events(&contents, &calendar_name, date_format, time_format, offset)
+            .map_err(|err| format!("could not parse {}: {}", path, err))?;
+    if ignored.zoned > 0 {
+        eprintln!(
+            "warning: {}: {} event(s) with a TZID are taken to be at UTC{}, timezones aren't read",
+            path, ignored.zoned, offset
+        );
+    }
+    if ignored.recurring > 0 {
+        eprintln!(
+            "warning: {}: {} recurring event(s) only notify at their first occurrence",
+            path, ignored.recurring
+        );
+    }
+    Ok(events)
+}
+
+/// What [`parse_events`] couldn't take into account, for warning about.
+#[derive(Debug, Default, PartialEq)]
+struct Ignored {
+    /// Events with a start or end in a named timezone.
+    zoned: usize,
+    /// Events with a recurrence rule.
+    recurring: usize,
+}
+
+fn parse_events(
+    contents: &str,
+    calendar_name: &str,
+    date_format: &str,
+    time_format: &str,
+    offset: UtcOffset,
+) -> Result<(Vec<KhalEvent>, Ignored), String> {
+    let calendar: Calendar = contents.parse()?;
+    let mut ignored = Ignored::default();
+    let mut events = Vec::new();
+    for event in calendar.components.iter().filter_map(|c| c.as_event()) {
+        let start = match event.get_start().and_then(|start| to_local(start, offset)) {
+            Some(start) => start,
+            None => continue,
+        };
+        let end = event
+            .get_end()
+            .and_then(|end| to_local(end, offset))
+            .unwrap_or(start);
+        let all_day = matches!(start, Local::Date(_));
+        let recurring = event.property_value("RRULE").is_some();
+        if recurring {
+            ignored.recurring += 1;
+        }
+        if [event.get_start(), event.get_end()].iter().any(|value| {
+            matches!(
+                value,
+                Some(DatePerhapsTime::DateTime(
+                    CalendarDateTime::WithTimezone { .. }
+                ))
+            )
+        }) {
+            ignored.zoned += 1;
+        }
+        let (start_date, start_time) = start.format(date_format, time_format);
+        let (end_date, end_time) = end.inclusive_end().format(date_format, time_format);
+        events.push(KhalEvent {
+            title: event.get_summary().unwrap_or_default().to_owned(),
+            description: event.get_description().unwrap_or_default().to_owned(),
+            location: event.get_location().unwrap_or_default().to_owned(),
+            uid: event.get_uid().unwrap_or_default().to_owned(),
+            calendar: calendar_name.to_owned(),
+            start_end_time_style: if all_day {
+                String::new()
+            } else {
+                format!("{}-{}", start_time, end_time)
+            },
+            start_date,
+            start_time,
+            end_date,
+            end_time,
+            repeat_symbol: if recurring {
+                REPEAT_SYMBOL.to_owned()
+            } else {
+                String::new()
+            },
+            all_day,
+            attendees: Vec::new(),
+            organizer: event
+                .property_value("ORGANIZER")
+                .map(|organizer| organizer.trim_start_matches("mailto:").to_owned())
+                .unwrap_or_default(),
+            extra: IndexMap::new(),
+            start: Some(start.assume_offset(offset)),
+            end: Some(end.assume_offset(offset)),
+            started: false,
+        });
+    }
+    Ok((events, ignored))
+}
+
+/// An iCalendar date or datetime, in local time.
+#[derive(Clone, Copy)]
+enum Local {
+    Date(Date),
+    DateTime(PrimitiveDateTime),
+}
+
+impl Local {
+    /// khal gives the last day of all-day events, where iCalendar gives the day after it.
+    fn inclusive_end(self) -> Local {
+        match self {
+            Local::Date(date) => Local::Date(date.previous_day()),
+            datetime => datetime,
+        }
+    }
+
+    /// Dates are taken as midnight at their start.
+    fn assume_offset(self, offset: UtcOffset) -> OffsetDateTime {
+        match self {
+            Local::Date(date) => date.midnight().assume_offset(offset),
+            Local::DateTime(datetime) => datetime.assume_offset(offset),
+        }
+    }
+
+    /// The date and time as khal would print them, the time being empty for dates.
+    fn format(self, date_format: &str, time_format: &str) -> (String, String) {
+        match self {
+            Local::Date(date) => (date.format(date_format), String::new()),
+            Local::DateTime(datetime) => (
+                datetime.date().format(date_format),
+                datetime.time().format(time_format),
+            ),
+        }
+    }
+}
+
+fn to_local(value: DatePerhapsTime, offset: UtcOffset) -> Option<Local> {
+    let naive = match value {
+        DatePerhapsTime::Date(date) => {
+            return Date::parse(date.format("%Y-%m-%d").to_string(), "%F")
+                .ok()
+                .map(Local::Date)
+        }
+        DatePerhapsTime::DateTime(CalendarDateTime::Utc(utc)) => {
+            let utc = OffsetDateTime::from_unix_timestamp(utc.timestamp()).to_offset(offset);
+            return Some(Local::DateTime(PrimitiveDateTime::new(
+                utc.date(),
+                utc.time(),
+            )));
+        }
+        DatePerhapsTime::DateTime(CalendarDateTime::Floating(naive)) => naive,
+        DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { date_time, .. }) => date_time,
+    };
+    PrimitiveDateTime::parse(naive.format("%Y-%m-%d %H:%M:%S").to_string(), "%F %T")
+        .ok()
+        .map(Local::DateTime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::offset;
+
+    fn parse(events: &str, offset: UtcOffset) -> (Vec<KhalEvent>, Ignored) {
+        let contents = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:test\r\n{}END:VCALENDAR\r\n",
+            events.replace('\n', "\r\n")
+        );
+        parse_events(&contents, "work", "%F", "%H:%M", offset).unwrap()
+    }
+
+    #[test]
+    fn all_day_end_is_last_day() {
+        let (events, _) = parse(
+            "BEGIN:VEVENT\nUID:a\nSUMMARY:Offsite\nDTSTART;VALUE=DATE:20240102\n\
+             DTEND;VALUE=DATE:20240104\nEND:VEVENT\n",
+            offset!(UTC),
+        );
+        let event = &events[0];
+        assert!(event.all_day);
+        assert_eq!(event.start_date, "2024-01-02");
+        assert_eq!(event.end_date, "2024-01-03");
+        assert_eq!(event.start_end_time_style, "");
+        assert_eq!(event.calendar, "work");
+    }
+
+    #[test]
+    fn utc_times_are_converted_to_offset() {
+        let (events, _) = parse(
+            "BEGIN:VEVENT\nUID:a\nSUMMARY:Standup\nDTSTART:20240101T233000Z\n\
+             DTEND:20240102T001500Z\nEND:VEVENT\n",
+            offset!(+2),
+        );
+        let event = &events[0];
+        assert_eq!(event.start_date, "2024-01-02");
+        assert_eq!(event.start_time, "01:30");
+        assert_eq!(event.end_time, "02:15");
+        assert_eq!(event.start_end_time_style, "01:30-02:15");
+        assert_eq!(event.start.unwrap().unix_timestamp(), 1_704_151_800);
+    }
+
+    #[test]
+    fn missing_end_is_start() {
+        let (events, _) = parse(
+            "BEGIN:VEVENT\nUID:a\nSUMMARY:Call\nDTSTART:20240102T101500\nEND:VEVENT\n",
+            offset!(UTC),
+        );
+        let event = &events[0];
+        assert_eq!(event.end_date, "2024-01-02");
+        assert_eq!(event.end_time, "10:15");
+        assert_eq!(event.start, event.end);
+    }
+
+    #[test]
+    fn counts_ignored_timezones_and_recurrences() {
+        let (events, ignored) = parse(
+            "BEGIN:VEVENT\nUID:a\nSUMMARY:Sync\nDTSTART;TZID=Europe/Berlin:20240102T101500\n\
+             DTEND;TZID=Europe/Berlin:20240102T110000\nRRULE:FREQ=WEEKLY\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:b\nSUMMARY:Call\nDTSTART:20240102T101500\nEND:VEVENT\n",
+            offset!(UTC),
+        );
+        assert_eq!(
+            ignored,
+            Ignored {
+                zoned: 1,
+                recurring: 1
+            }
+        );
+        assert_eq!(events[0].start_time, "10:15");
+        assert_eq!(events[0].repeat_symbol, REPEAT_SYMBOL);
+        assert_eq!(events[1].repeat_symbol, "");
+    }
+}
@@ -0,0 +1,472 @@
+use crate::which;
+use indexmap::IndexMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Output};
+use time::{
+    Date, Duration, Format, NumericalDuration, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset,
+};
+
+pub const JSON_FIELDS: [&str; 12] = [
+    "title",
+    "description",
+    "location",
+    "uid",
+    "calendar",
+    "start-date",
+    "start-time",
+    "end-date",
+    "end-time",
+    "start-end-time-style",
+    "repeat-symbol",
+    "all-day",
+];
+
+/// Left out of the query and made up from the start and end times instead if khal doesn't know it.
+pub const TIME_STYLE_FIELD: &str = "start-end-time-style";
+
+/// Only requested when attendees are shown, since older versions of khal don't know the field.
+pub const ATTENDEES_FIELD: &str = "attendees";
+
+/// Only requested when filtering by `--role`, for the same reason.
+pub const ORGANIZER_FIELD: &str = "organizer";
+
+/// Marks placeholders for fields requested with `--json-fields-extra`, such as `{extra.url}`.
+const EXTRA_PREFIX: &str = "extra.";
+
+/// How much of khal's output to echo back when it can't be parsed.
+const OUTPUT_PREVIEW_CHARS: usize = 200;
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct KhalEvent {
+    pub title: String,
+    pub description: String,
+    pub location: String,
+    pub uid: String,
+    pub calendar: String,
+    pub start_date: String,
+    pub start_time: String,
+    pub end_date: String,
+    pub end_time: String,
+    /// Empty from a khal that doesn't know the field, until [`KhalEvent::resolve_times`].
+    #[serde(default)]
+    pub start_end_time_style: String,
+    pub repeat_symbol: String,
+    pub all_day: bool,
+    #[serde(default, deserialize_with = "deserialize_attendees")]
+    pub attendees: Vec<String>,
+    #[serde(default)]
+    pub organizer: String,
+    /// Fields requested with `--json-fields-extra`, which aren't otherwise used, in the order khal
+    /// gave them so that they're printed back the same way.
+    #[serde(flatten)]
+    pub extra: IndexMap<String, String>,
+    /// Filled in by [`KhalEvent::resolve_times`], as khal's formats aren't known when parsing.
+    #[serde(skip_deserializing, serialize_with = "serialize_rfc3339")]
+    pub start: Option<OffsetDateTime>,
+    /// Filled in along with `start`.
+    #[serde(skip_deserializing, serialize_with = "serialize_rfc3339")]
+    pub end: Option<OffsetDateTime>,
+    /// Whether the event had already started when khal was queried.
+    #[serde(skip_deserializing)]
+    pub started: bool,
+}
+
+impl KhalEvent {
+    pub fn is_all_day(&self) -> bool {
+        self.all_day
+    }
+
+    /// Identifies this occurrence of the event, as recurring events share their uid.
+    ///
+    /// Once [`KhalEvent::resolve_times`] has run this goes by the resolved start, in UTC, so that
+    /// rewriting the shown times with [`KhalEvent::show_in`] doesn't change it.
+    pub fn id(&self) -> String {
+        let uid = if self.uid.is_empty() {
+            &self.title
+        } else {
+            &self.uid
+        };
+        match self.start {
+            Some(start) => format!(
+                "{} {}",
+                uid,
+                start.to_offset(UtcOffset::UTC).format(Format::Rfc3339)
+            ),
+            None => format!("{} {} {}", uid, self.start_date, self.start_time),
+        }
+    }
+
+    /// Parses the start and end dates and times using the formats khal is configured with.
+    ///
+    /// All-day events have no times, so for filtering they're taken to start at midnight of their
+    /// date and end at midnight after their last day, though they're never marked as started.
+    pub fn resolve_times(&mut self, date_format: &str, time_format: &str, offset: UtcOffset) {
+        self.start = self.parse_datetime(
+            &self.start_date,
+            &self.start_time,
+            date_format,
+            time_format,
+            offset,
+        );
+        self.end = self
+            .parse_datetime(
+                &self.end_date,
+                &self.end_time,
+                date_format,
+                time_format,
+                offset,
+            )
+            .map(|end| if self.all_day { end + 1.days() } else { end });
+        if self.start_end_time_style.is_empty() && !self.all_day {
+            // As khal has it for events within a day, which is also how ics events are shown.
+            self.start_end_time_style = format!("{}-{}", self.start_time, self.end_time);
+        }
+        self.started = !self.all_day
+            && self
+                .start
+                .is_some_and(|start| start <= OffsetDateTime::now_utc());
+    }
+
+    /// Rewrites the event's dates and times as they are at `offset`, noting `zone` after its time
+    /// style, so that an event from a calendar abroad shows in that calendar's own time.
+    pub fn show_in(&mut self, zone: &str, offset: UtcOffset, date_format: &str, time_format: &str) {
+        let (start, end) = match (self.start, self.end) {
+            (Some(start), Some(end)) if !self.all_day => {
+                (start.to_offset(offset), end.to_offset(offset))
+            }
+            _ => return,
+        };
+        self.start_date = start.format(date_format);
+        self.start_time = start.format(time_format);
+        self.end_date = end.format(date_format);
+        self.end_time = end.format(time_format);
+        self.start_end_time_style = if self.start_date == self.end_date {
+            format!("{}-{} {}", self.start_time, self.end_time, zone)
+        } else {
+            format!(
+                "{} {}-{} {} {}",
+                self.start_date, self.start_time, self.end_date, self.end_time, zone
+            )
+        };
+    }
+
+    fn parse_datetime(
+        &self,
+        date: &str,
+        time: &str,
+        date_format: &str,
+        time_format: &str,
+        offset: UtcOffset,
+    ) -> Option<OffsetDateTime> {
+        let date = Date::parse(date, date_format).ok()?;
+        let time = if self.all_day {
+            Time::midnight()
+        } else {
+            Time::parse(time, time_format).ok()?
+        };
+        Some(PrimitiveDateTime::new(date, time).assume_offset(offset))
+    }
+
+    /// How long the event lasts, if both its start and end could be parsed.
+    pub fn duration(&self) -> Option<Duration> {
+        Some(self.end? - self.start?)
+    }
+
+    /// Until when khal can still list this event as not started, which for all-day events is the
+    /// whole of their day.
+    pub fn listed_until(&self) -> Option<OffsetDateTime> {
+        if self.all_day {
+            self.start.map(|start| start + 1.days())
+        } else {
+            self.start
+        }
+    }
+
+    /// Looks up a field by its khal template name, for expanding placeholders.
+    pub fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "title" => Some(self.title.clone()),
+            "description" => Some(self.description.clone()),
+            "location" => Some(self.location.clone()),
+            "uid" => Some(self.uid.clone()),
+            "calendar" => Some(self.calendar.clone()),
+            "start-date" => Some(self.start_date.clone()),
+            "start-time" => Some(self.start_time.clone()),
+            "end-date" => Some(self.end_date.clone()),
+            "end-time" => Some(self.end_time.clone()),
+            "start-end-time-style" => Some(self.start_end_time_style.clone()),
+            "repeat-symbol" => Some(self.repeat_symbol.clone()),
+            "all-day" => Some(self.all_day.to_string()),
+            "attendees" => Some(self.attendees.join(", ")),
+            "organizer" => Some(self.organizer.clone()),
+            _ if name.starts_with(EXTRA_PREFIX) => {
+                self.extra.get(&name[EXTRA_PREFIX.len()..]).cloned()
+            }
+            _ => None,
+        }
+    }
+}
+
+fn serialize_rfc3339<S: Serializer>(
+    datetime: &Option<OffsetDateTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    datetime
+        .map(|datetime| datetime.format(Format::Rfc3339))
+        .serialize(serializer)
+}
+
+/// khal reports attendees as a single comma separated string, but accept a list as well.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Attendees {
+    Joined(String),
+    List(Vec<String>),
+}
+
+fn deserialize_attendees<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    let attendees = match Attendees::deserialize(deserializer)? {
+        Attendees::Joined(joined) => joined.split(',').map(str::to_owned).collect(),
+        Attendees::List(list) => list,
+    };
+    Ok(attendees
+        .into_iter()
+        .map(|a| a.trim().to_owned())
+        .filter(|a| !a.is_empty())
+        .collect())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Failed {
+        status: ExitStatus,
+        stderr: String,
+    },
+    NoOutput {
+        stderr: String,
+    },
+    Parse {
+        source: serde_json::Error,
+        output: String,
+        stderr: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Failed { status, stderr } => {
+                match status.code() {
+                    Some(code) => write!(f, "khal exited with code {}", code)?,
+                    None => write!(f, "khal was terminated by a signal")?,
+                }
+                write_stderr(f, stderr)
+            }
+            Error::NoOutput { stderr } => {
+                write!(f, "khal exited successfully but printed no events")?;
+                write_stderr(f, stderr)
+            }
+            Error::Parse {
+                source,
+                output,
+                stderr,
+            } => {
+                write!(f, "khal output of unexpected format: {}", source)?;
+                write!(f, "\noutput began with: {:?}", preview(output))?;
+                write_stderr(f, stderr)
+            }
+        }
+    }
+}
+
+fn write_stderr(f: &mut fmt::Formatter<'_>, stderr: &str) -> fmt::Result {
+    if stderr.trim().is_empty() {
+        Ok(())
+    } else {
+        write!(f, "\nkhal stderr: {}", stderr.trim())
+    }
+}
+
+/// Finds the khal executable, looking `program` up in `PATH` unless it's already a path.
+pub fn resolve_program(program: &str) -> Result<PathBuf, String> {
+    which::find(program).map_err(|err| format!("{}, see --khal-path", err))
+}
+
+/// Parses the events out of a finished khal process.
+///
+/// khal may print warnings ahead of the JSON and descriptions aren't guaranteed to be valid
+/// UTF-8, so invalid bytes are replaced and any lines before the start of the JSON are skipped.
+/// The arrays of every day khal printed are joined in order. A khal that failed or printed
+/// nothing is reported along with whatever it wrote to stderr.
+pub fn parse_output(output: &Output) -> Result<Vec<KhalEvent>, Error> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        return Err(Error::Failed {
+            status: output.status,
+            stderr,
+        });
+    }
+    if stdout.trim().is_empty() {
+        return Err(Error::NoOutput { stderr });
+    }
+    // `khal list` prints an array for each day in the range, while `khal at` prints just one.
+    serde_json::Deserializer::from_str(skip_leading_lines(&stdout))
+        .into_iter::<Vec<KhalEvent>>()
+        .try_fold(Vec::new(), |mut events, day| {
+            events.extend(day?);
+            Ok(events)
+        })
+        .map_err(|source| Error::Parse {
+            source,
+            output: stdout.into_owned(),
+            stderr,
+        })
+}
+
+impl Error {
+    /// Whether khal failed because it doesn't know the JSON `field` it was asked for.
+    pub fn rejects_field(&self, field: &str) -> bool {
+        match self {
+            Error::Failed { stderr, .. } => stderr.contains(field),
+            _ => false,
+        }
+    }
+}
+
+/// Drops every line before the first one that looks like the start of a JSON document.
+fn skip_leading_lines(stdout: &str) -> &str {
+    let mut offset = 0;
+    for line in stdout.split_inclusive('\n') {
+        if line.trim_start().starts_with(['[', '{']) {
+            return &stdout[offset..];
+        }
+        offset += line.len();
+    }
+    stdout
+}
+
+fn preview(output: &str) -> String {
+    output.chars().take(OUTPUT_PREVIEW_CHARS).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn output(status: i32, stdout: &str, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(status),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    fn event_json(title: &str) -> String {
+        format!(
+            r#"{{"title": "{}", "description": "", "location": "", "uid": "", "calendar": "",
+            "start-date": "", "start-time": "", "end-date": "", "end-time": "",
+            "repeat-symbol": "", "all-day": false}}"#,
+            title
+        )
+    }
+
+    fn titles(events: Vec<KhalEvent>) -> Vec<String> {
+        events.into_iter().map(|event| event.title).collect()
+    }
+
+    #[test]
+    fn parses_single_array() {
+        let stdout = format!("[{}, {}]\n", event_json("a"), event_json("b"));
+        let events = parse_output(&output(0, &stdout, "")).unwrap();
+        assert_eq!(titles(events), ["a", "b"]);
+    }
+
+    #[test]
+    fn joins_arrays_of_each_day() {
+        let stdout = format!("[{}]\n[]\n[{}]\n", event_json("a"), event_json("b"));
+        let events = parse_output(&output(0, &stdout, "")).unwrap();
+        assert_eq!(titles(events), ["a", "b"]);
+        assert!(parse_output(&output(0, "[]\n[]\n", "")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_warnings_before_json() {
+        let stdout = format!("warning: something\n[{}]\n", event_json("a"));
+        let events = parse_output(&output(0, &stdout, "")).unwrap();
+        assert_eq!(titles(events), ["a"]);
+    }
+
+    #[test]
+    fn reports_failed_khal_with_stderr() {
+        let err = parse_output(&output(256, "", "unknown calendar\n")).unwrap_err();
+        assert!(matches!(err, Error::Failed { .. }));
+        let message = err.to_string();
+        assert!(message.contains("exited with code 1"), "{}", message);
+        assert!(message.contains("unknown calendar"), "{}", message);
+    }
+
+    /// An event from `start` to `end`, written in the `%F` and `%R` formats the tests resolve with.
+    fn timed(start: OffsetDateTime, end: OffsetDateTime) -> KhalEvent {
+        KhalEvent {
+            start_date: start.format("%F"),
+            start_time: start.format("%R"),
+            end_date: end.format("%F"),
+            end_time: end.format("%R"),
+            ..KhalEvent::default()
+        }
+    }
+
+    #[test]
+    fn event_from_two_minutes_ago_has_started() {
+        let now = OffsetDateTime::now_utc();
+        let mut event = timed(now - 2.minutes(), now + 1.hours());
+        event.resolve_times("%F", "%R", UtcOffset::UTC);
+        assert!(event.started);
+        assert!(event.start.unwrap() <= now - 1.minutes());
+
+        let mut upcoming = timed(now + 2.minutes(), now + 1.hours());
+        upcoming.resolve_times("%F", "%R", UtcOffset::UTC);
+        assert!(!upcoming.started);
+    }
+
+    #[test]
+    fn all_day_event_spans_midnight_to_midnight() {
+        let mut event = KhalEvent {
+            start_date: "2024-01-02".to_owned(),
+            end_date: "2024-01-03".to_owned(),
+            all_day: true,
+            ..KhalEvent::default()
+        };
+        event.resolve_times("%F", "%R", UtcOffset::hours(2));
+        let midnight = |day| {
+            Date::parse(day, "%F")
+                .unwrap()
+                .midnight()
+                .assume_offset(UtcOffset::hours(2))
+        };
+        assert_eq!(event.start, Some(midnight("2024-01-02")));
+        assert_eq!(event.end, Some(midnight("2024-01-04")));
+        assert_eq!(event.start.unwrap().offset(), UtcOffset::hours(2));
+        assert!(!event.started);
+        assert!(event.start_end_time_style.is_empty());
+    }
+
+    #[test]
+    fn showing_in_another_zone_keeps_id() {
+        let start = OffsetDateTime::now_utc() + 1.hours();
+        let mut event = timed(start, start + 1.hours());
+        event.uid = "abc@example.com".to_owned();
+        event.resolve_times("%F", "%R", UtcOffset::UTC);
+        let id = event.id();
+        event.show_in("Asia/Tokyo", UtcOffset::hours(9), "%F", "%R");
+        assert_ne!(event.start_time, start.format("%R"));
+        assert_eq!(event.id(), id);
+    }
+}
@@ -0,0 +1,110 @@
+use std::str::FromStr;
+
+/// The fixed phrases khal-notify adds to notifications itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phrase {
+    /// The default `--started-prefix`.
+    Started,
+    AllDay,
+    /// `{n}` is the number of minutes.
+    InMinutes,
+    MinutesAgo,
+    Now,
+    /// Leads the list of attendees.
+    With,
+    /// Stands for the attendees over `--max-attendees`, `{n}` of them.
+    More,
+    /// The label of the button added by `--dismiss-action`.
+    Dismiss,
+    /// Leads the labels of the buttons added by `--link-actions`, which go on with the link's host.
+    Open,
+    /// The title of the notification sent by `--notify-empty`.
+    NoEvents,
+    /// What `--countdown` prints, where `{time}` is the time left and `{title}` the event's title.
+    Until,
+}
+
+const ENGLISH: &[(Phrase, &str)] = &[
+    (Phrase::Started, "STARTED: "),
+    (Phrase::AllDay, "All day"),
+    (Phrase::InMinutes, "in {n} min"),
+    (Phrase::MinutesAgo, "{n} min ago"),
+    (Phrase::Now, "now"),
+    (Phrase::With, "with:"),
+    (Phrase::More, "+{n} more"),
+    (Phrase::Dismiss, "Dismiss"),
+    (Phrase::Open, "Open"),
+    (Phrase::NoEvents, "No upcoming events"),
+    (Phrase::Until, "{time} until {title}"),
+];
+
+const GERMAN: &[(Phrase, &str)] = &[
+    (Phrase::Started, "BEGONNEN: "),
+    (Phrase::AllDay, "Ganztägig"),
+    (Phrase::InMinutes, "in {n} Min."),
+    (Phrase::MinutesAgo, "vor {n} Min."),
+    (Phrase::Now, "jetzt"),
+    (Phrase::With, "mit:"),
+    (Phrase::More, "+{n} weitere"),
+    (Phrase::Dismiss, "Verwerfen"),
+    (Phrase::Open, "Öffnen"),
+    (Phrase::NoEvents, "Keine anstehenden Termine"),
+    (Phrase::Until, "{time} bis {title}"),
+];
+
+const JAPANESE: &[(Phrase, &str)] = &[
+    (Phrase::Started, "開始済み: "),
+    (Phrase::AllDay, "終日"),
+    (Phrase::InMinutes, "{n}分後"),
+    (Phrase::MinutesAgo, "{n}分前"),
+    (Phrase::Now, "今"),
+    (Phrase::With, "参加者:"),
+    (Phrase::More, "他{n}名"),
+    (Phrase::Dismiss, "閉じる"),
+    (Phrase::Open, "開く"),
+    (Phrase::NoEvents, "予定はありません"),
+    (Phrase::Until, "{title}まで{time}"),
+];
+
+/// Which language the phrases are in, from `--locale`.
+#[derive(Debug, Clone, Copy)]
+pub struct Locale(&'static [(Phrase, &'static str)]);
+
+impl Locale {
+    /// The phrase in this locale, or in English if it hasn't been translated.
+    pub fn text(self, phrase: Phrase) -> &'static str {
+        [self.0, ENGLISH]
+            .iter()
+            .find_map(|table| table.iter().find(|(p, _)| *p == phrase))
+            .map_or("", |(_, text)| text)
+    }
+
+    /// The phrase with `{n}` replaced by `n`.
+    pub fn count(self, phrase: Phrase, n: i64) -> String {
+        self.text(phrase).replace("{n}", &n.to_string())
+    }
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale(ENGLISH)
+    }
+}
+
+impl FromStr for Locale {
+    type Err = String;
+
+    /// Takes a language code, ignoring any region or encoding after it as in `de_DE.UTF-8`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let language = s.split(['_', '-', '.']).next().unwrap_or(s);
+        match language {
+            "en" | "C" => Ok(Locale(ENGLISH)),
+            "de" => Ok(Locale(GERMAN)),
+            "ja" => Ok(Locale(JAPANESE)),
+            _ => Err(format!(
+                "unsupported locale {:?}, expected one of en, de or ja",
+                s
+            )),
+        }
+    }
+}
@@ -1,57 +1,75 @@
 #![feature(iter_intersperse)]
 
-use clap::{App, Arg};
+mod at;
+mod audit;
+mod check;
+mod countdown;
+mod desc;
+mod dnd;
+mod explain;
+mod icon;
+mod ics;
+mod khal;
+mod locale;
+mod notify;
+mod output;
+mod preview;
+mod progress;
+mod render;
+mod role;
+mod shell;
+mod sort;
+mod state;
+mod status;
+mod template;
+mod tz;
+mod which;
+
+use at::Window;
+use audit::AuditLog;
+use clap::{App, Arg, ArgMatches};
+use desc::{Markup, Normalization};
+use icon::Icons;
+use khal::{KhalEvent, ATTENDEES_FIELD, JSON_FIELDS, ORGANIZER_FIELD, TIME_STYLE_FIELD};
+use locale::{Locale, Phrase};
+use notify::{Notifier, ReplaceKey, Urgency};
+use output::FilteredOut;
 use regex::Regex;
-use serde::Deserialize;
+use render::Notification;
+use role::Role;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use sort::SortKey;
+use state::State;
+use status::Status;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::Path;
-use std::process::Command;
-use std::{array, time::Duration};
+use std::process::{self, Command};
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicBool};
+use std::time::{Duration, Instant};
 use std::{sync::Arc, thread};
-use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
-use unicode_segmentation::UnicodeSegmentation;
+use time::{NumericalDuration, OffsetDateTime, Time, UtcOffset};
 
 const MINUTE_OFFSET: &str = "10";
 const DESC_CHARS: &str = "200";
+const MAX_ATTENDEES: &str = "5";
+/// Used when neither `--timezone` nor the local timezone gives an offset.
+const DEFAULT_UTC_OFFSET: i8 = 9;
 
-const JSON_FIELDS: [&str; 5] = [
-    "title",
-    "description",
-    "start-end-time-style",
-    "repeat-symbol",
-    "all-day",
-];
-const URL_REGEX: &str = r"(https?://(www\.)?)?[-a-zA-Z0-9@:%._\+~#=]{1,256}\.[a-zA-Z0-9()]{1,6}\b([-a-zA-Z0-9()@:%_\+.~#?&//=]*)";
-
-#[derive(Deserialize, Debug, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-struct KhalEvent {
-    title: String,
-    description: String,
-    start_end_time_style: String,
-    repeat_symbol: String,
-    all_day: bool,
-}
-
-impl KhalEvent {
-    fn is_all_day(&self) -> bool {
-        self.all_day
-    }
-
-    fn formatted_title(&self) -> String {
-        if self.repeat_symbol.is_empty() {
-            self.title.clone()
-        } else {
-            self.title.clone() + " " + &self.repeat_symbol
-        }
-    }
-}
+/// Whether it's been pointed out that the notify command is missing.
+static MISSING_NOTIFY_WARNED: AtomicBool = AtomicBool::new(false);
 
 pub fn main() {
     let config_default = directories::BaseDirs::new()
         .map(|d| d.config_dir().join(Path::new("khal/config")))
-        .map(|pb| pb.to_str().map(str::to_owned))
-        .flatten()
+        .and_then(|pb| pb.to_str().map(str::to_owned))
         .unwrap_or_else(|| "khal.conf".to_owned());
+    let state_default = directories::BaseDirs::new()
+        .map(|d| d.data_local_dir().join(Path::new("khal-notify/state.json")))
+        .and_then(|pb| pb.to_str().map(str::to_owned))
+        .unwrap_or_else(|| "khal-notify-state.json".to_owned());
     let matches = App::new("khal-notify")
         .version("1.0")
         .author("Mattori Birnbaum <mattori.birnbaum@gmail.com>")
@@ -64,20 +82,224 @@ pub fn main() {
                 .help("khal config location")
                 .default_value(&config_default),
         )
+        .arg(
+            Arg::with_name("collection")
+                .long("collection")
+                .value_name("NAME")
+                .multiple(true)
+                .number_of_values(1)
+                .help("only have khal read this calendar from the --config file, passed on as --include-calendar; can be given more than once"),
+        )
         .arg(
             Arg::with_name("description length")
                 .short("l")
                 .long("desc-length")
                 .value_name("CHARS")
-                .help("character limit for event description")
+                .allow_hyphen_values(true)
+                .help("character limit for event description, or none (or a negative number) to not truncate it")
                 .default_value(DESC_CHARS),
         )
+        .arg(
+            Arg::with_name("min description length")
+                .long("min-desc-length")
+                .value_name("CHARS")
+                .help("leave out descriptions shorter than this once stripped, such as \"n/a\"")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("title max length")
+                .long("title-max-length")
+                .value_name("CHARS")
+                .help("character limit for event titles, not counting the repeat symbol"),
+        )
+        .arg(
+            Arg::with_name("no repeat symbol")
+                .long("no-repeat-symbol")
+                .help("leave out the symbol khal marks recurring events with, for fonts without it"),
+        )
+        .arg(
+            Arg::with_name("repeat symbol replace")
+                .long("repeat-symbol-replace")
+                .value_name("STR")
+                .conflicts_with("no repeat symbol")
+                .help("mark recurring events with this instead of khal's repeat symbol"),
+        )
+        .arg(
+            Arg::with_name("title lines")
+                .long("title-lines")
+                .value_name("collapse|first|N")
+                .help("join the lines of multi-line titles with spaces, keep only the first, or keep the first N"),
+        )
+        .arg(
+            Arg::with_name("title dedup suffix")
+                .long("title-dedup-suffix")
+                .value_name("WHAT")
+                .possible_values(&["time", "calendar"])
+                .help("when several notifications would have the same title, add their start time, or the date for all-day events, or their calendar to it in brackets"),
+        )
+        .arg(
+            Arg::with_name("no description")
+                .long("no-description")
+                .help("leave event descriptions and their links out entirely"),
+        )
         .arg(
             Arg::with_name("include all day")
                 .short("a")
                 .long("all-day")
                 .help("include all day events"),
         )
+        .arg(
+            Arg::with_name("since")
+                .long("since")
+                .value_name("MINUTES")
+                .help("also include events that started up to this many minutes ago"),
+        )
+        .arg(
+            Arg::with_name("started prefix")
+                .long("started-prefix")
+                .value_name("TEXT")
+                .help("put in front of the titles of events that have already started [default: STARTED: , or its translation with --locale]"),
+        )
+        .arg(
+            Arg::with_name("title from desc")
+                .long("title-from-desc")
+                .help("for events without a title, use the first line of the description as the title instead"),
+        )
+        .arg(
+            Arg::with_name("default title")
+                .long("default-title")
+                .value_name("TITLE")
+                .help("title to use for events that are otherwise left without one"),
+        )
+        .arg(
+            Arg::with_name("calendar prefix")
+                .long("calendar-prefix")
+                .help("put the calendar name in front of titles"),
+        )
+        .arg(
+            Arg::with_name("calendar prefix format")
+                .long("calendar-prefix-format")
+                .value_name("TEMPLATE")
+                .help("what --calendar-prefix puts in front of titles, with {calendar} and other fields filled in")
+                .default_value("[{calendar}]"),
+        )
+        .arg(
+            Arg::with_name("min lead")
+                .long("min-lead")
+                .value_name("MINUTES")
+                .help("skip events starting sooner than this many minutes from now"),
+        )
+        .arg(
+            Arg::with_name("max lead")
+                .long("max-lead")
+                .value_name("MINUTES")
+                .help("skip events starting later than this many minutes from now, regardless of AT"),
+        )
+        .arg(
+            Arg::with_name("body order")
+                .long("body-order")
+                .value_name("PARTS")
+                .help("comma-separated parts of the body in the order to show them, out of desc, links, time, title, location, attendees and relative (\"in 10 min\"); parts not listed are left out")
+                .default_value(render::DEFAULT_BODY_ORDER),
+        )
+        .arg(
+            Arg::with_name("compact")
+                .long("compact")
+                .help("put the body on a single line, made of the time, title and location unless --body-order is given"),
+        )
+        .arg(
+            Arg::with_name("compact separator")
+                .long("compact-sep")
+                .value_name("TEXT")
+                .help("what the parts of a --compact body are joined with")
+                .default_value(" · "),
+        )
+        .arg(
+            Arg::with_name("body prefix")
+                .long("body-prefix")
+                .value_name("TEXT")
+                .allow_hyphen_values(true)
+                .help("put at the start of every body, where \\n is a newline"),
+        )
+        .arg(
+            Arg::with_name("body suffix")
+                .long("body-suffix")
+                .value_name("TEXT")
+                .allow_hyphen_values(true)
+                .help("put at the end of every body, where \\n is a newline"),
+        )
+        .arg(
+            Arg::with_name("after")
+                .long("after")
+                .value_name("HH:MM")
+                .help("skip events starting earlier in the day than this"),
+        )
+        .arg(
+            Arg::with_name("before")
+                .long("before")
+                .value_name("HH:MM")
+                .help("skip events starting at or after this time of day, wrapping past midnight if it's earlier than --after"),
+        )
+        .arg(
+            Arg::with_name("quiet hours")
+                .long("quiet-hours")
+                .value_name("HH:MM-HH:MM")
+                .help("don't send notifications between these times of day, wrapping past midnight if the end is earlier, such as 22:00-07:00; the events are skipped unless --defer-quiet is given"),
+        )
+        .arg(
+            Arg::with_name("defer quiet")
+                .long("defer-quiet")
+                .requires("quiet hours")
+                .help("keep notifications from --quiet-hours in the state file and send them with the first run after quiet hours end"),
+        )
+        .arg(
+            Arg::with_name("role")
+                .long("role")
+                .value_name("ROLE")
+                .possible_values(&["organizer", "attendee", "any"])
+                .default_value("any")
+                .help("only notify for events organized by one of --me, or by someone else; events without an organizer are always notified"),
+        )
+        .arg(
+            Arg::with_name("me")
+                .long("me")
+                .value_name("ADDRESS")
+                .multiple(true)
+                .number_of_values(1)
+                .required_ifs(&[("role", "organizer"), ("role", "attendee")])
+                .help("email address of yours, looked for in event organizers for --role"),
+        )
+        .arg(
+            Arg::with_name("max events per calendar")
+                .long("max-events-per-calendar")
+                .value_name("COUNT")
+                .help("notify for at most this many of the soonest events from each calendar"),
+        )
+        .arg(
+            Arg::with_name("sort by")
+                .long("sort-by")
+                .value_name("KEY")
+                .possible_values(&["start", "title", "calendar", "duration"])
+                .help("order events by this before notifying, after --max-events-per-calendar has picked the soonest")
+                .default_value("start"),
+        )
+        .arg(
+            Arg::with_name("reverse")
+                .long("reverse")
+                .help("sort in descending order, such as longest first with --sort-by duration"),
+        )
+        .arg(
+            Arg::with_name("show attendees")
+                .long("show-attendees")
+                .help("list event attendees after the event time"),
+        )
+        .arg(
+            Arg::with_name("max attendees")
+                .long("max-attendees")
+                .value_name("COUNT")
+                .help("number of attendees to list before summarizing the rest")
+                .default_value(MAX_ATTENDEES),
+        )
         .arg(
             Arg::with_name("date format")
                 .short("d")
@@ -99,8 +321,7 @@ pub fn main() {
                 .short("z")
                 .long("timezone")
                 .value_name("HOURS")
-                .help("utc offset of local timezone")
-                .default_value("+9"),
+                .help("utc offset of local timezone, by default that of TZ or the system timezone, or +9 if neither can be found"),
         )
         .arg(
             Arg::with_name("strip regex")
@@ -112,137 +333,1604 @@ pub fn main() {
                 .allow_hyphen_values(true)
                 .help("regex for text to strip from event descriptions"),
         )
+        .arg(
+            Arg::with_name("strip regex file")
+                .long("strip-regex-file")
+                .value_name("PATH")
+                .help("file of strip regexes to use before any --strip-regex, one per line, as PATTERN or PATTERN=REPLACEMENT with \\= for an = in the pattern; lines starting with # are comments"),
+        )
+        .arg(
+            Arg::with_name("at format")
+                .long("at-format")
+                .value_name("FORMAT")
+                .help("format used to parse AT when it is a datetime")
+                .default_value(at::DEFAULT_FORMAT),
+        )
+        .arg(
+            Arg::with_name("debug khal cmd")
+                .long("debug-khal-cmd")
+                .help("print the khal command to stderr before running it"),
+        )
+        .arg(
+            Arg::with_name("print only")
+                .long("print-only")
+                .requires("debug khal cmd")
+                .help("exit after printing the khal command instead of running it"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("MODE")
+                .possible_values(&["notify", "json", "json-compact", "jsonl"])
+                .help("send notifications, or print them as a JSON object along with the events that were filtered out and why, as a bare JSON array, or as JSON lines, in the format described by schema/output-v1.json")
+                .default_value("notify"),
+        )
+        .arg(
+            Arg::with_name("output socket")
+                .long("output-socket")
+                .value_name("PATH")
+                .conflicts_with("output")
+                .help("write the notifications as JSON lines to this Unix socket or named pipe instead, skipping them if nothing reads it"),
+        )
+        .arg(
+            Arg::with_name("preview")
+                .long("preview")
+                .value_name("PATH")
+                .conflicts_with_all(&["output", "output socket"])
+                .help("write the notifications to this HTML file, coloured by urgency, instead of sending them"),
+        )
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .conflicts_with_all(&["output", "output socket", "count only", "emit debug json"])
+                .help("print the matching events as a table of date, time, title and calendar instead of notifying"),
+        )
+        .arg(
+            Arg::with_name("countdown")
+                .long("countdown")
+                .conflicts_with_all(&["output", "output socket", "list", "preview", "watch", "morning digest"])
+                .help("instead of notifying, print how long is left until the next matching event starts, like \"0:09:32 until Standup\", rewriting the line each second until it does"),
+        )
+        .arg(
+            Arg::with_name("no tty")
+                .long("no-tty")
+                .requires("countdown")
+                .help("print each --countdown update on a line of its own instead of rewriting one, for status bars that read lines"),
+        )
+        .arg(
+            Arg::with_name("count only")
+                .long("count-only")
+                .help("print the number of matching events instead of notifying"),
+        )
+        .arg(
+            Arg::with_name("emit debug json")
+                .long("emit-debug-json")
+                .help("print the parsed events left after filtering as JSON instead of notifying"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .value_name("SECONDS")
+                .help("keep running, checking khal this often and handling each event only once, until terminated"),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .value_name("TITLE")
+                .conflicts_with_all(&["watch", "count only", "emit debug json"])
+                .help("print how the event whose title contains this is turned into a notification, then exit without notifying"),
+        )
+        .arg(
+            Arg::with_name("explain first")
+                .long("explain-first")
+                .requires("explain")
+                .help("explain the first of several events matching --explain instead of failing"),
+        )
+        .arg(
+            Arg::with_name("status file")
+                .long("status-file")
+                .value_name("FILE")
+                .help("write a JSON summary of the run to this file"),
+        )
+        .arg(
+            Arg::with_name("max runtime warn")
+                .long("max-runtime-warn")
+                .value_name("SECONDS")
+                .help("warn when a run takes longer than this, with how long khal and sending the notifications took"),
+        )
+        .arg(
+            Arg::with_name("audit log")
+                .long("audit-log")
+                .value_name("FILE")
+                .help("append each notification sent to this file as a line of JSON"),
+        )
+        .arg(
+            Arg::with_name("state file")
+                .long("state-file")
+                .value_name("FILE")
+                .help("where to remember things between runs, such as dismissed events")
+                .default_value(&state_default),
+        )
+        .arg(
+            Arg::with_name("dedup")
+                .long("dedup")
+                .help("remember events notified for in --state-file, and don't notify for them again in later runs until they've started"),
+        )
+        .arg(
+            Arg::with_name("dedup window")
+                .long("dedup-window")
+                .value_name("SECONDS")
+                .requires("dedup")
+                .help("with --dedup, notify for events again once this long has passed since the last time"),
+        )
+        .arg(
+            Arg::with_name("on change")
+                .long("on-change")
+                .help("only notify when the events found differ from the last run's, going by which events they are and when they start, and then notify for all of them; meant for --watch, where it takes the place of notifying each event once")
+        )
+        .arg(
+            Arg::with_name("dismiss action")
+                .long("dismiss-action")
+                .help("add a Dismiss button to notifications, which stops that occurrence of the event from notifying again; the notify command has to support --action and print the chosen one"),
+        )
+        .arg(
+            Arg::with_name("link actions")
+                .long("link-actions")
+                .help("add a button to notifications for each link in the event's description, up to --max-link-actions, which opens it with the default handler; the notify command has to support --action and print the chosen one, and if it fails the buttons are left off"),
+        )
+        .arg(
+            Arg::with_name("max link actions")
+                .long("max-link-actions")
+                .value_name("COUNT")
+                .default_value("3")
+                .help("most link buttons to add, leaving any other links just in the body"),
+        )
+        .arg(
+            Arg::with_name("strip preset")
+                .long("strip-preset")
+                .value_name("NAMES")
+                .multiple(true)
+                .number_of_values(1)
+                .help("comma separated built-in strip regexes to use before any --strip-regex-file or --strip-regex: zoom, meet, signature"),
+        )
+        .arg(
+            Arg::with_name("strip order")
+                .long("strip-order")
+                .value_name("ORDER")
+                .possible_values(&["before", "after"])
+                .help("strip descriptions before or after truncating them, which also decides whether links in stripped text are kept")
+                .default_value("before"),
+        )
+        .arg(
+            Arg::with_name("no links")
+                .long("no-links")
+                .conflicts_with("sort links")
+                .help("don't look for links in descriptions"),
+        )
+        .arg(
+            Arg::with_name("sort links")
+                .long("sort-links")
+                .help("list links from truncated descriptions alphabetically instead of in order of appearance"),
+        )
+        .arg(
+            Arg::with_name("link mode")
+                .long("link-mode")
+                .value_name("MODE")
+                .possible_values(&["append", "replace", "inline"])
+                .default_value("append")
+                .help("leave URLs in the visible description as they are, take them out and list them with the links from the truncated part, or make them into links in place"),
+        )
+        .arg(
+            Arg::with_name("flatten whitespace")
+                .long("flatten-whitespace")
+                .help("collapse runs of whitespace in descriptions to a single newline, or a single space if there's no line break in them, before truncating"),
+        )
+        .arg(
+            Arg::with_name("description markdown")
+                .long("desc-markdown")
+                .help("show Markdown links in descriptions as their text, as anchors with --markup pango, and list their URLs with the other links"),
+        )
+        .arg(
+            Arg::with_name("link separator")
+                .long("link-sep")
+                .value_name("STR")
+                .default_value("\n")
+                .hide_default_value(true)
+                .help("put between links from truncated descriptions [default: newline]"),
+        )
+        .arg(
+            Arg::with_name("locale")
+                .long("locale")
+                .value_name("LANGUAGE")
+                .help("language of the phrases added to notifications, such as the started prefix and relative times, out of en, de and ja")
+                .default_value("en"),
+        )
+        .arg(
+            Arg::with_name("markup")
+                .long("markup")
+                .value_name("LANGUAGE")
+                .possible_values(&["pango", "plain", "markdown"])
+                .help("markup the notifier understands, used for links and attendee lists")
+                .default_value("pango"),
+        )
+        .arg(
+            Arg::with_name("normalize")
+                .long("normalize")
+                .value_name("FORM")
+                .possible_values(&["nfc", "nfd", "none"])
+                .help("unicode normalization applied to event titles and descriptions")
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("notify cmd")
+                .long("notify-cmd")
+                .value_name("COMMAND")
+                .help("command run with the title and body of each notification")
+                .default_value(notify::DEFAULT_COMMAND),
+        )
+        .arg(
+            Arg::with_name("notify arg")
+                .long("notify-arg")
+                .value_name("ARG")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .help("extra argument for the notify command, where {field} is replaced with the event's khal field")
+        )
+        .arg(
+            Arg::with_name("notify host")
+                .long("notify-host")
+                .value_name("USER@HOST")
+                .help("run the notify command on another machine over ssh")
+        )
+        .arg(
+            Arg::with_name("calendar urgency")
+                .long("calendar-urgency")
+                .value_name("CALENDAR=URGENCY,...")
+                .help("pass an urgency of low, normal or critical for events from these calendars"),
+        )
+        .arg(
+            Arg::with_name("calendar tz")
+                .long("calendar-tz")
+                .value_name("CALENDAR=ZONE,...")
+                .help("show the times of events from these calendars in another timezone, given as an IANA name like Europe/Berlin or as hours from UTC"),
+        )
+        .arg(
+            Arg::with_name("auto icon")
+                .long("auto-icon")
+                .help("pass an icon for birthdays, all-day events and events with a link, which are usually video calls"),
+        )
+        .arg(
+            Arg::with_name("icon map")
+                .long("icon-map")
+                .value_name("KIND=ICON,...")
+                .requires("auto icon")
+                .help("icons to use instead of the defaults for birthday (emblem-favorite), all-day (x-office-calendar) and video (camera-web), or none if empty"),
+        )
+        .arg(
+            Arg::with_name("icon from url host")
+                .long("icon-from-url-host")
+                .help("pass the icon of the meeting service an event's links are for, such as Zoom for zoom.us or google-meet for meet.google.com, ahead of any --auto-icon one"),
+        )
+        .arg(
+            Arg::with_name("host icon map")
+                .long("host-icon-map")
+                .value_name("HOST=ICON,...")
+                .requires("icon from url host")
+                .help("icons for more link hosts, or instead of the built-in ones for zoom.us, meet.google.com, teams.microsoft.com, meet.jit.si and webex.com, or none if empty; a host also covers its subdomains"),
+        )
+        .arg(
+            Arg::with_name("urgent notify cmd")
+                .long("urgent-notify-cmd")
+                .value_name("COMMAND")
+                .help("notify command to use instead for events that are critical according to --calendar-urgency"),
+        )
+        .arg(
+            Arg::with_name("repeat critical")
+                .long("repeat-critical")
+                .value_name("COUNT:SECONDS")
+                .help("resend notifications for critical events this many more times, this many seconds apart, for up to an hour in all"),
+        )
+        .arg(
+            Arg::with_name("replace")
+                .long("replace")
+                .help("have the daemon replace an event's earlier notification, such as from --repeat-critical, rather than show another"),
+        )
+        .arg(
+            Arg::with_name("replace key")
+                .long("replace-key")
+                .value_name("title|uid|fixed:ID")
+                .help("like --replace, but replacing earlier notifications with the same title, from any occurrence of the event, or with the given ID, which makes each replace the last"),
+        )
+        .arg(
+            Arg::with_name("thread id")
+                .long("thread-id")
+                .value_name("ID")
+                .min_values(0)
+                .require_equals(true)
+                .help("group the run's notifications on daemons that support it by passing a thread-id hint, made up from the current time unless given as --thread-id=ID"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("have notifications close after this long, rather than when the daemon decides"),
+        )
+        .arg(
+            Arg::with_name("persistent")
+                .long("persistent")
+                .conflicts_with("timeout")
+                .help("keep notifications up until they're closed by hand"),
+        )
+        .arg(
+            Arg::with_name("timeout low")
+                .long("timeout-low")
+                .value_name("SECONDS")
+                .help("--timeout for low urgency notifications, taking precedence over --timeout and --persistent"),
+        )
+        .arg(
+            Arg::with_name("timeout normal")
+                .long("timeout-normal")
+                .value_name("SECONDS")
+                .help("--timeout for normal urgency notifications, including events without an urgency from --calendar-urgency"),
+        )
+        .arg(
+            Arg::with_name("timeout critical")
+                .long("timeout-critical")
+                .value_name("SECONDS")
+                .help("--timeout for critical notifications"),
+        )
+        .arg(
+            Arg::with_name("notify timeout")
+                .long("notify-timeout")
+                .value_name("SECONDS")
+                .help("kill the notify command if it hasn't exited after this long"),
+        )
+        .arg(
+            Arg::with_name("no notify send wait")
+                .long("no-notify-send-wait")
+                .conflicts_with_all(&["dismiss action", "link actions", "notify timeout"])
+                .help("don't wait for each notify command to finish before moving on, at the cost of not noticing when one fails; they're waited for before exiting"),
+        )
+        .arg(
+            Arg::with_name("notify empty")
+                .long("notify-empty")
+                .conflicts_with("watch")
+                .help("send a \"No upcoming events\" notification when no events match, only when notifying"),
+        )
+        .arg(
+            Arg::with_name("strict notify")
+                .long("strict-notify")
+                .help("fail if the notify command can't be found, instead of printing notifications to stderr"),
+        )
+        .arg(
+            Arg::with_name("no threads")
+                .long("no-threads")
+                .help("notify for events one at a time in sorted order, instead of all at once from a thread each"),
+        )
+        .arg(
+            Arg::with_name("rate limit")
+                .long("rate-limit")
+                .value_name("PER_SECOND")
+                .help("send at most this many notifications a second, spacing them out in sorted order so that the notification daemon doesn't drop any"),
+        )
+        .arg(
+            Arg::with_name("batch size")
+                .long("batch-size")
+                .value_name("COUNT")
+                .requires("batch delay")
+                .help("send notifications this many at a time in sorted order, pausing for --batch-delay between batches"),
+        )
+        .arg(
+            Arg::with_name("batch delay")
+                .long("batch-delay")
+                .value_name("SECONDS")
+                .requires("batch size")
+                .help("how long to pause between batches of --batch-size notifications, on top of any --rate-limit spacing"),
+        )
+        .arg(
+            Arg::with_name("body stdin")
+                .long("body-stdin")
+                .help("write the notification body to the notify command's stdin instead of passing it as an argument"),
+        )
+        .arg(
+            Arg::with_name("max arg length")
+                .long("max-arg-length")
+                .value_name("BYTES")
+                .help("longest body passed as an argument before it's truncated with a warning")
+                .default_value(notify::MAX_ARG_LENGTH),
+        )
+        .arg(
+            Arg::with_name("respect dnd")
+                .long("respect-dnd")
+                .help("don't notify while dunst is paused, according to dunstctl"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .help("show a spinner while khal runs, if stderr is a terminal"),
+        )
+        .arg(
+            Arg::with_name("json fields extra")
+                .long("json-fields-extra")
+                .value_name("NAME")
+                .multiple(true)
+                .number_of_values(1)
+                .help("also ask khal for this field, to use as {extra.NAME} in templates; fields khal-notify already uses keep their usual placeholder instead, like {title}"),
+        )
+        .arg(
+            Arg::with_name("khal path")
+                .long("khal-path")
+                .value_name("PATH")
+                .help("khal executable to run, looked up in PATH if it's just a name")
+                .default_value("khal"),
+        )
+        .arg(
+            Arg::with_name("ics")
+                .long("ics")
+                .value_name("FILE")
+                .conflicts_with("debug khal cmd")
+                .help(
+                    "read events from this iCalendar file instead of running khal; times with a \
+                     TZID are taken to be in --timezone, and recurring events only show up at \
+                     their first occurrence",
+                ),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("check that khal, its config, the notify command, the timezone and strip regexes are all usable, without notifying"),
+        )
+        .arg(
+            Arg::with_name("range")
+                .long("range")
+                .value_names(&["START", "END"])
+                .number_of_values(2)
+                .help("list events between two times, each given like AT, instead of checking a single time"),
+        )
+        .arg(
+            Arg::with_name("morning digest")
+                .long("morning-digest")
+                .conflicts_with_all(&["range", "at file", "AT", "list", "preview"])
+                .help("send a single notification titled with today's date listing every all-day and timed event of the day, including those that already started, in order of when they start; --sort-by and --reverse are ignored"),
+        )
+        .arg(
+            Arg::with_name("at file")
+                .long("at-file")
+                .value_name("FILE")
+                .conflicts_with("range")
+                .help("check for events at each time in this file, one per line given like AT, along with AT if that's given too; blank lines and # comments are ignored"),
+        )
         .arg(
             Arg::with_name("AT")
                 .value_name("TIME")
                 .multiple(true)
-                .help("minutes in the future or datetime (YYYY-mm-dd HH:MM) to check for events")
+                .help("minutes in the future, a phrase like \"tomorrow 9am\" or \"in 2 hours\", RFC 3339 datetime, or datetime (see --at-format) to check for events")
                 .default_value(MINUTE_OFFSET),
         )
         .get_matches();
 
+    if matches.is_present("check") {
+        process::exit(if check::run(&matches) { 0 } else { 1 });
+    }
+
+    // Looking zones up points TZ at each of them in turn, which has to happen before there are
+    // other threads that could be reading the environment, so it's done once for every run and
+    // any error is reported by each of them.
+    let calendar_tz = matches
+        .value_of("calendar tz")
+        .map(tz::parse_calendar_tz)
+        .transpose()
+        .map(Option::unwrap_or_default);
+
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("could not install signal handler");
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            notify::kill_running();
+            process::exit(128 + signal);
+        }
+    });
+
+    let watch = matches
+        .value_of("watch")
+        .map(|secs| parse_number(secs, "watch interval").map(Duration::from_secs))
+        .transpose()
+        .unwrap_or_else(|err| fail(err));
+    let max_runtime = matches
+        .value_of("max runtime warn")
+        .map(|secs| parse_number(secs, "max runtime warn").map(Duration::from_secs_f64))
+        .transpose()
+        .unwrap_or_else(|err| fail(err));
+    let mut seen = HashMap::new();
+    loop {
+        notify::reap_detached();
+        let mut status = Status::default();
+        let started = Instant::now();
+        let result = run(
+            &matches,
+            &mut status,
+            &calendar_tz,
+            watch.map(|_| &mut seen),
+        );
+        let runtime = started.elapsed();
+        if max_runtime.is_some_and(|max| runtime > max) {
+            eprintln!(
+                "warning: run took {:.2}s ({:.2}s waiting for khal, {:.2}s sending notifications)",
+                runtime.as_secs_f64(),
+                status.fetch_time.as_secs_f64(),
+                status.dispatch_time.as_secs_f64()
+            );
+        }
+        if let Some(path) = matches.value_of("status file") {
+            status.error = result.as_ref().err().cloned();
+            if let Err(err) = status.write(path) {
+                eprintln!("could not write status file: {}", err);
+            }
+        }
+        match (result, watch) {
+            (Err(err), None) => fail(err),
+            (Ok(()), None) => break,
+            (Err(err), Some(interval)) => {
+                eprintln!("{}", err);
+                thread::sleep(interval);
+            }
+            (Ok(()), Some(interval)) => thread::sleep(interval),
+        }
+    }
+    notify::wait_detached();
+}
+
+/// Queries khal once and handles the events it returns.
+///
+/// When watching, `seen` holds the events already handled by earlier runs along with when they
+/// start, so that each event is only handled once. `calendar_tz` is `--calendar-tz` as looked up
+/// before starting.
+fn run(
+    matches: &ArgMatches,
+    status: &mut Status,
+    calendar_tz: &Result<HashMap<String, (String, UtcOffset)>, String>,
+    mut seen: Option<&mut HashMap<String, Option<OffsetDateTime>>>,
+) -> Result<(), String> {
+    let calendar_tz = calendar_tz.as_ref().map_err(String::clone)?;
     let config = matches.value_of("config").unwrap();
-    let at: String = matches
-        .values_of("AT")
-        .unwrap()
-        .into_iter()
-        .intersperse(" ")
-        .collect();
-    let desc_chars = matches
-        .value_of("description length")
-        .unwrap()
-        .parse()
-        .expect("description length is not a number");
-    let include_all_day = matches.is_present("include all day");
+    let at: String = matches.values_of("AT").unwrap().intersperse(" ").collect();
+    let desc_chars = match matches.value_of("description length").unwrap() {
+        "none" => None,
+        chars => match parse_number::<i64>(chars, "description length")? {
+            chars if chars < 0 => None,
+            chars => Some(chars as usize),
+        },
+    };
+    let digest = matches.is_present("morning digest");
+    let include_all_day = digest || matches.is_present("include all day");
+    let minutes = |name: &str| {
+        matches
+            .value_of(name)
+            .map(|m| parse_number::<i64>(m, name).map(NumericalDuration::minutes))
+            .transpose()
+    };
+    let min_lead = minutes("min lead")?;
+    let max_lead = minutes("max lead")?;
+    let since = minutes("since")?;
+    let after = parse_clock(matches.value_of("after"), "--after")?;
+    let before = parse_clock(matches.value_of("before"), "--before")?;
+    let quiet_hours = match matches
+        .value_of("quiet hours")
+        .map(|hours| hours.split_once('-'))
+    {
+        Some(Some((start, end))) => Some((
+            parse_clock(Some(start), "--quiet-hours")?.unwrap(),
+            parse_clock(Some(end), "--quiet-hours")?.unwrap(),
+        )),
+        Some(None) => return Err("--quiet-hours expects START-END, such as 22:00-07:00".to_owned()),
+        None => None,
+    };
+    let max_per_calendar = matches
+        .value_of("max events per calendar")
+        .map(|m| parse_number::<usize>(m, "max events per calendar"))
+        .transpose()?;
+    let no_description = matches.is_present("no description");
+    let show_attendees = matches.is_present("show attendees");
+    let max_attendees = parse_number(matches.value_of("max attendees").unwrap(), "max attendees")?;
     let date_format = matches.value_of("date format").unwrap();
     let time_format = matches.value_of("time format").unwrap();
-    let utc_offset = UtcOffset::hours(
-        matches
-            .value_of("utc offset")
-            .unwrap()
-            .parse::<i8>()
-            .expect("utc offset of unexpected format"),
-    );
-    let strip_regexes = Arc::new(
+    let at_format = matches.value_of("at format").unwrap();
+    let utc_offset = match matches.value_of("utc offset") {
+        Some(hours) => UtcOffset::hours(parse_number(hours, "utc offset")?),
+        None => tz::local_offset().unwrap_or_else(|| UtcOffset::hours(DEFAULT_UTC_OFFSET)),
+    };
+    let mut strip_regexes = desc::strip_presets(
         matches
-            .values_of("strip regex")
-            .map(|i| i.map(Regex::new).flatten().collect())
-            .unwrap_or_else(|| Vec::new()),
+            .values_of("strip preset")
+            .into_iter()
+            .flatten()
+            .flat_map(|presets| presets.split(',')),
+    )?;
+    if let Some(path) = matches.value_of("strip regex file") {
+        strip_regexes.extend(desc::read_strip_file(path)?);
+    }
+    for pattern in matches.values_of("strip regex").into_iter().flatten() {
+        let regex =
+            Regex::new(pattern).map_err(|err| format!("--strip-regex {:?}: {}", pattern, err))?;
+        strip_regexes.push((regex, String::new()));
+    }
+    let strip_order = matches.value_of("strip order").unwrap().parse()?;
+    let normalization: Normalization = matches.value_of("normalize").unwrap().parse()?;
+    let markup: Markup = matches.value_of("markup").unwrap().parse()?;
+    let locale: Locale = matches.value_of("locale").unwrap().parse()?;
+    let compact = matches.is_present("compact");
+    let body_order = if compact && matches.occurrences_of("body order") == 0 {
+        render::COMPACT_BODY_ORDER
+    } else {
+        matches.value_of("body order").unwrap()
+    };
+    let body_order = body_order
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    let render_options = render::Options {
+        body_order,
+        description: if no_description {
+            None
+        } else {
+            Some(desc::Options {
+                strip_regexes,
+                strip_order,
+                max_chars: desc_chars,
+                min_chars: parse_number(
+                    matches.value_of("min description length").unwrap(),
+                    "min description length",
+                )?,
+                links: !matches.is_present("no links"),
+                sort_links: matches.is_present("sort links"),
+                link_separator: matches.value_of("link separator").unwrap().to_owned(),
+                link_mode: matches.value_of("link mode").unwrap().parse()?,
+                markdown_links: matches.is_present("description markdown"),
+                flatten_whitespace: matches.is_present("flatten whitespace"),
+                markup,
+            })
+        },
+        max_attendees: if show_attendees {
+            Some(max_attendees)
+        } else {
+            None
+        },
+        title_from_description: matches.is_present("title from desc"),
+        default_title: matches.value_of("default title").map(str::to_owned),
+        started_prefix: match matches.value_of("started prefix") {
+            Some(prefix) => prefix.to_owned(),
+            None => locale.text(Phrase::Started).to_owned(),
+        },
+        calendar_prefix: if matches.is_present("calendar prefix") {
+            Some(
+                matches
+                    .value_of("calendar prefix format")
+                    .unwrap()
+                    .to_owned(),
+            )
+        } else {
+            None
+        },
+        compact_separator: if compact {
+            Some(matches.value_of("compact separator").unwrap().to_owned())
+        } else {
+            None
+        },
+        body_prefix: unescape(matches.value_of("body prefix").unwrap_or_default()),
+        body_suffix: unescape(matches.value_of("body suffix").unwrap_or_default()),
+        locale,
+        markup,
+        title_max_length: matches
+            .value_of("title max length")
+            .map(|max| parse_number(max, "title max length"))
+            .transpose()?,
+        title_lines: matches
+            .value_of("title lines")
+            .map(str::parse)
+            .transpose()?,
+        title_dedup_suffix: matches
+            .value_of("title dedup suffix")
+            .map(str::parse)
+            .transpose()?,
+    };
+    let output_socket = matches.value_of("output socket");
+    let output_mode: output::Mode = if output_socket.is_some() {
+        output::Mode::Jsonl
+    } else {
+        matches.value_of("output").unwrap().parse()?
+    };
+    let calendar_urgency = matches
+        .value_of("calendar urgency")
+        .map(notify::parse_calendar_urgency)
+        .transpose()?
+        .unwrap_or_default();
+    let seconds = |name: &str| {
+        matches.value_of(name).map(|seconds| {
+            seconds
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| format!("{} is not a number: {:?}", name, seconds))
+        })
+    };
+    let expire_time = if matches.is_present("persistent") {
+        Some(Duration::ZERO)
+    } else {
+        seconds("timeout").transpose()?
+    };
+    let mut urgency_expire_time = HashMap::new();
+    for (urgency, name) in [
+        (Urgency::Low, "timeout low"),
+        (Urgency::Normal, "timeout normal"),
+        (Urgency::Critical, "timeout critical"),
+    ] {
+        if let Some(expire_time) = seconds(name).transpose()? {
+            urgency_expire_time.insert(urgency, expire_time);
+        }
+    }
+    let icons = if matches.is_present("auto icon") {
+        Some(Icons::with_overrides(matches.value_of("icon map"))?)
+    } else {
+        None
+    };
+    let icons = if matches.is_present("icon from url host") {
+        Some(
+            icons
+                .unwrap_or_default()
+                .with_host_icons(matches.value_of("host icon map"))?,
+        )
+    } else {
+        icons
+    };
+    let notifier = Arc::new(Notifier {
+        command: matches.value_of("notify cmd").unwrap().to_owned(),
+        urgent_command: matches.value_of("urgent notify cmd").map(str::to_owned),
+        icons,
+        expire_time,
+        urgency_expire_time,
+        host: matches.value_of("notify host").map(str::to_owned),
+        args: matches
+            .values_of("notify arg")
+            .map(|args| args.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        calendar_urgency,
+        repeat_critical: matches
+            .value_of("repeat critical")
+            .map(str::parse)
+            .transpose()?,
+        replace: match matches.value_of("replace key") {
+            Some(key) => Some(key.parse()?),
+            None if matches.is_present("replace") => Some(ReplaceKey::Occurrence),
+            None => None,
+        },
+        thread_id: if matches.is_present("thread id") {
+            Some(matches.value_of("thread id").map_or_else(
+                || format!("khal-notify-{}", OffsetDateTime::now_utc().unix_timestamp()),
+                str::to_owned,
+            ))
+        } else {
+            None
+        },
+        dismiss_action: matches.is_present("dismiss action"),
+        link_actions: if matches.is_present("link actions") {
+            parse_number(
+                matches.value_of("max link actions").unwrap(),
+                "max link actions",
+            )?
+        } else {
+            0
+        },
+        detach: matches.is_present("no notify send wait"),
+        timeout: seconds("notify timeout").transpose()?,
+        body_stdin: matches.is_present("body stdin"),
+        stderr_fallback: !matches.is_present("strict notify"),
+        locale,
+        max_arg_length: parse_number(
+            matches.value_of("max arg length").unwrap(),
+            "max arg length",
+        )?,
+    });
+
+    let windows: Vec<Window> = match matches.values_of("range") {
+        // From midnight to midnight, so that what's already started today is in the digest too.
+        None if digest => {
+            let today = OffsetDateTime::now_utc()
+                .to_offset(utc_offset)
+                .date()
+                .midnight()
+                .assume_offset(utc_offset);
+            vec![Window::Range(today, today + 1.days())]
+        }
+        Some(mut range) => {
+            let mut bound = || {
+                at::parse_target(range.next().unwrap(), at_format, utc_offset)
+                    .map_err(|err| err.to_string())
+            };
+            vec![Window::Range(bound()?, bound()?)]
+        }
+        None => {
+            let mut targets = match matches.value_of("at file") {
+                Some(path) => at::read_targets(path)?,
+                None => Vec::new(),
+            };
+            // AT always has a value, but with --at-file it's only used if given explicitly.
+            if targets.is_empty() || matches.occurrences_of("AT") > 0 {
+                targets.push(at);
+            }
+            targets
+                .iter()
+                .map(|target| {
+                    at::parse_target(target, at_format, utc_offset)
+                        .map(Window::At)
+                        .map_err(|err| err.to_string())
+                })
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    let khal_path = matches.value_of("khal path").unwrap();
+    let mut query_args: Vec<String> = Vec::new();
+    if since.is_none() && !digest {
+        query_args.push("--notstarted".to_owned());
+    }
+    query_args.push("--json".to_owned());
+    query_args.extend(
+        JSON_FIELDS
+            .iter()
+            .copied()
+            .intersperse("--json")
+            .map(str::to_owned),
     );
+    if show_attendees {
+        query_args.extend(["--json".to_owned(), ATTENDEES_FIELD.to_owned()]);
+    }
+    let role: Role = matches.value_of("role").unwrap().parse()?;
+    if role != Role::Any {
+        query_args.extend(["--json".to_owned(), ORGANIZER_FIELD.to_owned()]);
+    }
+    // Scoped by khal itself, so calendars left out are never read at all.
+    for collection in matches.values_of("collection").into_iter().flatten() {
+        query_args.extend(["--include-calendar".to_owned(), collection.to_owned()]);
+    }
+    // Fields that are already requested keep their own placeholders.
+    for field in matches.values_of("json fields extra").into_iter().flatten() {
+        let requested = JSON_FIELDS.contains(&field)
+            || field == ATTENDEES_FIELD
+            || (field == ORGANIZER_FIELD && role != Role::Any);
+        if !requested {
+            query_args.extend(["--json".to_owned(), field.to_owned()]);
+        }
+    }
+    let khal_args: Vec<Vec<String>> = windows
+        .iter()
+        .map(|window| {
+            let mut khal_args: Vec<String> = vec!["--config".to_owned(), config.to_owned()];
+            match *window {
+                Window::Range(from, to) => {
+                    khal_args.push("list".to_owned());
+                    for bound in [from, to] {
+                        khal_args.extend([bound.format(date_format), bound.format(time_format)]);
+                    }
+                }
+                Window::At(target) => {
+                    khal_args.extend([
+                        "at".to_owned(),
+                        target.format(date_format),
+                        target.format(time_format),
+                    ]);
+                }
+            }
+            khal_args.extend(query_args.iter().cloned());
+            khal_args
+        })
+        .collect();
 
-    let url_regex = Arc::new(Regex::new(URL_REGEX).unwrap());
+    if matches.is_present("debug khal cmd") {
+        for khal_args in &khal_args {
+            let quoted: String = std::iter::once(shell::quote(khal_path))
+                .chain(khal_args.iter().map(|arg| shell::quote(arg)))
+                .intersperse(" ".into())
+                .collect();
+            eprintln!("{}", quoted);
+        }
+        if matches.is_present("print only") {
+            return Ok(());
+        }
+    }
 
-    let target = if at.contains(':') || at.contains(' ') {
-        PrimitiveDateTime::parse(at, "%F %R")
-            .expect("datetime offset of unexpected format")
-            .assume_offset(utc_offset)
+    let fetch_started = Instant::now();
+    let mut events = match matches.value_of("ics") {
+        Some(path) => {
+            let mut events = ics::read_events(path, date_format, time_format, utc_offset)?;
+            // Done by khal itself otherwise.
+            if let Some(collections) = matches.values_of("collection") {
+                let collections: Vec<&str> = collections.collect();
+                events.retain(|e| collections.contains(&e.calendar.as_str()));
+            }
+            let now = OffsetDateTime::now_utc();
+            events.retain(|e| {
+                e.start.is_some_and(|start| {
+                    windows
+                        .iter()
+                        .any(|window| window.overlaps(start, e.end.unwrap_or(start)))
+                        && (since.is_some() || digest || start >= now)
+                })
+            });
+            events
+        }
+        None => {
+            let spinner = matches
+                .is_present("progress")
+                .then(|| progress::Spinner::start("waiting for khal"));
+            let khal = khal::resolve_program(khal_path)?;
+            let mut events = Vec::new();
+            let query = |khal_args: &[String]| {
+                Command::new(&khal)
+                    .args(khal_args)
+                    .output()
+                    .map_err(|err| format!("could not execute {}: {}", khal.display(), err))
+            };
+            for khal_args in &khal_args {
+                let parsed = match khal::parse_output(&query(khal_args)?) {
+                    // Older khal doesn't have the time style, which resolve_times makes up.
+                    Err(err) if err.rejects_field(TIME_STYLE_FIELD) => {
+                        let mut khal_args = khal_args.clone();
+                        if let Some(i) = khal_args.iter().position(|arg| arg == TIME_STYLE_FIELD) {
+                            khal_args.drain(i - 1..=i);
+                        }
+                        khal::parse_output(&query(&khal_args)?)
+                    }
+                    parsed => parsed,
+                };
+                events.extend(parsed.map_err(|err| err.to_string())?);
+            }
+            drop(spinner);
+            if khal_args.len() > 1 {
+                // An event khal lists for several targets is only notified once.
+                let mut ids = HashSet::new();
+                events.retain(|e| ids.insert(e.id()));
+            }
+            events
+        }
+    };
+    status.fetch_time = fetch_started.elapsed();
+    let parsed_count = events.len();
+    let repeat_symbol = if matches.is_present("no repeat symbol") {
+        Some("")
     } else {
-        let offset_duration =
-            Duration::from_secs(at.parse::<u64>().expect("offset is not a number") * 60);
-        OffsetDateTime::now_utc().to_offset(utc_offset) + offset_duration
-    };
-
-    let khal_output = Command::new("khal")
-        .args(&[
-            "--config",
-            config,
-            "at",
-            &target.format(date_format),
-            &target.format(time_format),
-            "--notstarted",
-            "--json",
-        ])
-        .args(array::IntoIter::new(JSON_FIELDS).intersperse("--json"))
-        .output()
-        .expect("could not execute khal")
-        .stdout;
-
-    let mut events: Vec<KhalEvent> =
-        serde_json::from_slice(&khal_output).expect("khal output of unexpected format");
+        matches.value_of("repeat symbol replace")
+    };
+    for event in &mut events {
+        event.resolve_times(date_format, time_format, utc_offset);
+        // Replaced on the event, so titles, --list and {repeat-symbol} placeholders all agree.
+        if let Some(symbol) = repeat_symbol.filter(|_| !event.repeat_symbol.is_empty()) {
+            event.repeat_symbol = symbol.to_owned();
+        }
+        // Everything else goes by the resolved times, so only what's shown changes.
+        if let Some((zone, offset)) = calendar_tz.get(&event.calendar) {
+            event.show_in(zone, *offset, date_format, time_format);
+        }
+        if normalization != Normalization::None {
+            event.title = normalization.apply(&event.title);
+            event.description = normalization.apply(&event.description);
+        }
+    }
 
+    // Looked for before any filtering, so events that wouldn't be notified can be explained too.
+    if let Some(title) = matches.value_of("explain") {
+        let event = explain::find(&events, title, matches.is_present("explain first"))?;
+        return explain::print(event, &render_options, &notifier);
+    }
+
+    // Kept for --output json, to say why each event that khal returned wasn't notified.
+    let mut filtered_out = Vec::new();
     if !include_all_day {
-        events = events.into_iter().filter(|e| !e.is_all_day()).collect();
+        filter(&mut events, &mut filtered_out, "all day", |e| {
+            !e.is_all_day()
+        });
     }
 
-    let mut handles = Vec::with_capacity(events.len());
-    for event in events {
-        let strip_regexes = Arc::clone(&strip_regexes);
-        let url_regex = Arc::clone(&url_regex);
-        let handle = thread::spawn(move || {
-            let title = event.formatted_title();
+    if let Some(since) = since {
+        let earliest = OffsetDateTime::now_utc() - since;
+        filter(
+            &mut events,
+            &mut filtered_out,
+            "started before --since",
+            |e| e.start.is_none_or(|start| start >= earliest),
+        );
+    }
 
-            let stripped_desc = strip_regexes
-                .iter()
-                .fold(event.description.clone(), |d, regex| {
-                    regex.replace_all(&d, "").into_owned()
-                });
-            let mut short_desc = if desc_chars < stripped_desc.len() {
-                let mut desc_graphemes = stripped_desc.graphemes(true);
-                let mut short_desc =
-                    desc_graphemes.by_ref().take(desc_chars).collect::<String>() + "...";
-                for link in find_links(
-                    url_regex,
-                    desc_graphemes.by_ref().skip(desc_chars).collect(),
-                ) {
-                    short_desc += &link
-                }
-                short_desc
-            } else {
-                stripped_desc
+    if min_lead.is_some() || max_lead.is_some() {
+        let now = OffsetDateTime::now_utc();
+        filter(
+            &mut events,
+            &mut filtered_out,
+            "outside the lead time",
+            |e| {
+                e.start
+                    .is_none_or(|start| in_lead_window(start - now, min_lead, max_lead))
+            },
+        );
+    }
+
+    if after.is_some() || before.is_some() {
+        filter(
+            &mut events,
+            &mut filtered_out,
+            "outside --after and --before",
+            |e| {
+                e.start
+                    .is_none_or(|start| in_clock_window(start.time(), after, before))
+            },
+        );
+    }
+
+    if role != Role::Any {
+        let me: Vec<String> = matches
+            .values_of("me")
+            .into_iter()
+            .flatten()
+            .map(str::to_owned)
+            .collect();
+        filter(&mut events, &mut filtered_out, "not matching --role", |e| {
+            role.keeps(e, &me)
+        });
+    }
+
+    let dedup_window = matches
+        .value_of("dedup window")
+        .map(|seconds| {
+            seconds
+                .parse()
+                .map(|seconds: i64| seconds.seconds())
+                .map_err(|_| format!("dedup window is not a number: {:?}", seconds))
+        })
+        .transpose()?;
+    let state_file = matches.value_of("state file").unwrap();
+    let mut state = State::load(state_file)?;
+    let pruned = state.dismissed.len() + state.notified.len();
+    // Events that started within --since can still come back from khal, so they're kept until then.
+    let now = OffsetDateTime::now_utc();
+    state.prune(now - since.unwrap_or_default(), now);
+    let mut state_changed = state.dismissed.len() + state.notified.len() != pruned;
+    filter(&mut events, &mut filtered_out, "dismissed", |e| {
+        !state.is_dismissed(e)
+    });
+    let dedup = matches.is_present("dedup");
+    if dedup {
+        filter(&mut events, &mut filtered_out, "already notified", |e| {
+            !state.was_notified(e)
+        });
+    }
+
+    let on_change = matches.is_present("on change");
+    if on_change {
+        // Saved straight away, as the output modes return before the state is saved at the end.
+        let hash = state::event_set_hash(&events);
+        if state.event_set.as_ref() == Some(&hash) {
+            status.matched = events.len();
+            status.skipped = parsed_count - events.len();
+            return Ok(());
+        }
+        state.event_set = Some(hash);
+        state.save(state_file)?;
+    } else if let Some(seen) = seen.as_mut() {
+        let now = OffsetDateTime::now_utc();
+        seen.retain(|_, until| until.is_none_or(|until| until >= now));
+        filter(
+            &mut events,
+            &mut filtered_out,
+            "already seen while watching",
+            |e| !seen.contains_key(&e.id()),
+        );
+    }
+
+    // Applied after leaving out events already notified, so that with --watch the ones over the
+    // cap still get their turn.
+    if let Some(max) = max_per_calendar {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let reason = "over --max-events-per-calendar";
+        filter(&mut events, &mut filtered_out, reason, |e| {
+            let count = counts.entry(e.calendar.clone()).or_default();
+            *count += 1;
+            *count <= max
+        });
+        for (calendar, count) in counts.into_iter().filter(|&(_, count)| count > max) {
+            eprintln!(
+                "note: left out {} more events from calendar {:?}",
+                count - max,
+                calendar
+            );
+        }
+    }
+
+    // sort_by is stable, so events with equal keys stay in khal's order.
+    let sort_key: SortKey = if digest {
+        SortKey::Start
+    } else {
+        matches.value_of("sort by").unwrap().parse()?
+    };
+    if matches.is_present("reverse") && !digest {
+        events.sort_by(|a, b| sort_key.compare(a, b).reverse());
+    } else {
+        events.sort_by(|a, b| sort_key.compare(a, b));
+    }
+
+    let notifying = output_mode == output::Mode::Notify
+        && !matches.is_present("emit debug json")
+        && !matches.is_present("count only")
+        && !matches.is_present("list")
+        && !matches.is_present("preview")
+        && !matches.is_present("countdown");
+    // Checked before events are marked as seen, so that with --watch they're still notified once
+    // dunst is unpaused, if they haven't started by then.
+    if notifying && matches.is_present("respect dnd") && dnd::is_paused() {
+        if !events.is_empty() {
+            eprintln!(
+                "dunst is paused, holding back {} notifications",
+                events.len()
+            );
+        }
+        status.matched = events.len();
+        status.skipped = parsed_count - events.len();
+        return Ok(());
+    }
+
+    if let Some(seen) = seen.filter(|_| !on_change) {
+        for event in &events {
+            seen.insert(event.id(), event.listed_until());
+        }
+    }
+
+    status.matched = events.len();
+    status.skipped = parsed_count - events.len();
+    let diagnostics = output::Diagnostics {
+        meta: output::Meta {
+            targets: windows.iter().map(|window| window.rfc3339()).collect(),
+            timezone: utc_offset.format("%z"),
+            returned: parsed_count,
+            matched: events.len(),
+            filtered_out: filtered_out.len(),
+        },
+        filtered_out,
+    };
+
+    if matches.is_present("emit debug json") {
+        let json = serde_json::to_string_pretty(&events).map_err(|err| err.to_string())?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    if matches.is_present("count only") {
+        println!("{}", events.len());
+        return Ok(());
+    }
+
+    if matches.is_present("list") {
+        for line in output::table(&events, locale) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("countdown") {
+        return countdown::run(&events, locale, !matches.is_present("no tty"));
+    }
+
+    let quiet = quiet_hours.is_some_and(|(start, end)| {
+        let now = OffsetDateTime::now_utc().to_offset(utc_offset).time();
+        in_clock_window(now, Some(start), Some(end))
+    });
+
+    // Left for --notify-empty below when there's nothing today.
+    if digest && !events.is_empty() {
+        let today = OffsetDateTime::now_utc().to_offset(utc_offset).date();
+        let notification = render::render_digest(&events, today.format(date_format), locale);
+        // Not from any one event, so like --notify-empty there are no fields for placeholders.
+        let event = KhalEvent::default();
+        if output_mode != output::Mode::Notify {
+            let records = [output::Record {
+                notification: &notification,
+                event: &event,
+            }];
+            return match output_socket {
+                Some(path) => output::write_socket(path, &records),
+                None => output::print(output_mode, &records, &diagnostics),
             };
-            if !event.all_day {
-                if !short_desc.ends_with('\n') {
-                    short_desc += "\n";
+        }
+        if quiet {
+            if matches.is_present("defer quiet") {
+                state.defer(&event, &notification);
+                state.save(state_file)?;
+            }
+            return Ok(());
+        }
+        notifier.send(&event, &notification.title, &notification.body)?;
+        return Ok(());
+    }
+
+    let mut notifications = render::render_events(&events, &render_options);
+
+    if let Some(path) = matches.value_of("preview") {
+        let previews: Vec<_> = notifications
+            .iter()
+            .zip(&events)
+            .map(|(notification, event)| {
+                (
+                    notification,
+                    notifier.urgency(event).unwrap_or(Urgency::Normal),
+                )
+            })
+            .collect();
+        return preview::write(path, &previews);
+    }
+
+    if output_mode != output::Mode::Notify {
+        let records: Vec<_> = notifications
+            .iter()
+            .zip(&events)
+            .map(|(notification, event)| output::Record {
+                notification,
+                event,
+            })
+            .collect();
+        return match output_socket {
+            Some(path) => output::write_socket(path, &records),
+            None => output::print(output_mode, &records, &diagnostics),
+        };
+    }
+
+    // Everything from here on is sent, so this is the last point to hold it back. Events are marked
+    // as seen already, so with --watch they aren't picked up again once quiet hours end, and are
+    // only sent then if they were deferred.
+    if quiet {
+        if matches.is_present("defer quiet") && !events.is_empty() {
+            for (event, notification) in events.iter().zip(&notifications) {
+                state.defer(event, notification);
+            }
+            state.save(state_file)?;
+        }
+        return Ok(());
+    }
+    if !state.deferred.is_empty() {
+        let deferred: Vec<_> = state
+            .deferred
+            .drain(..)
+            .filter(|deferred| events.iter().all(|event| event.id() != deferred.id))
+            .collect();
+        state_changed = true;
+        // Not the whole event, so like --notify-empty there are few fields for placeholders.
+        let (deferred_events, deferred_notifications): (Vec<_>, Vec<_>) = deferred
+            .into_iter()
+            .map(|deferred| {
+                let event = KhalEvent {
+                    calendar: deferred.calendar,
+                    ..KhalEvent::default()
+                };
+                let notification = Notification {
+                    title: deferred.title,
+                    body: deferred.body,
+                };
+                (event, notification)
+            })
+            .unzip();
+        events.splice(0..0, deferred_events);
+        notifications.splice(0..0, deferred_notifications);
+    }
+
+    if events.is_empty() && matches.is_present("notify empty") {
+        // Not an event, so it has no fields for --notify-arg placeholders or --calendar-urgency.
+        notifier.send(&KhalEvent::default(), locale.text(Phrase::NoEvents), "")?;
+        return Ok(());
+    }
+
+    if let Some(err) = notifier.missing_command() {
+        if matches.is_present("strict notify") {
+            return Err(format!("{}, see --notify-cmd", err));
+        }
+        // With --watch this would otherwise be repeated every time.
+        if !MISSING_NOTIFY_WARNED.swap(true, atomic::Ordering::Relaxed) {
+            eprintln!(
+                "warning: {}, printing notifications to stderr instead (see --strict-notify)",
+                err
+            );
+        }
+    }
+
+    let audit_log = matches
+        .value_of("audit log")
+        .map(AuditLog::open)
+        .transpose()?
+        .map(Arc::new);
+    let sequential = matches.is_present("no threads");
+    // The time to leave between starting one send and the next.
+    let spacing = match matches.value_of("rate limit") {
+        Some(rate) => match rate
+            .parse::<f64>()
+            .ok()
+            .filter(|&rate| rate > 0.0)
+            .and_then(|rate| Duration::try_from_secs_f64(1.0 / rate).ok())
+        {
+            Some(spacing) => spacing,
+            None => return Err(format!("rate limit {:?} is not a positive number", rate)),
+        },
+        None => Duration::ZERO,
+    };
+    let batch_size: usize = matches
+        .value_of("batch size")
+        .map(|size| match size.parse() {
+            Ok(size) if size > 0 => Ok(size),
+            _ => Err(format!("batch size {:?} is not a positive number", size)),
+        })
+        .transpose()?
+        .unwrap_or(usize::MAX);
+    let batch_delay = seconds("batch delay").transpose()?.unwrap_or_default();
+    let due = |i: usize| send_due(i, spacing, batch_size, batch_delay);
+    let dispatch_started = Instant::now();
+    let results: Vec<Result<(KhalEvent, Option<String>), String>> = if sequential {
+        events
+            .into_iter()
+            .zip(notifications)
+            .enumerate()
+            .map(|(i, (event, notification))| {
+                // Sends that took longer than the spacing don't need any more waiting.
+                thread::sleep(due(i).saturating_sub(dispatch_started.elapsed()));
+                deliver(&notifier, audit_log.as_deref(), event, notification)
+            })
+            .collect()
+    } else {
+        let handles: Vec<_> = events
+            .into_iter()
+            .zip(notifications)
+            .enumerate()
+            .map(|(i, (event, notification))| {
+                let notifier = Arc::clone(&notifier);
+                let audit_log = audit_log.clone();
+                let delay = due(i);
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    deliver(&notifier, audit_log.as_deref(), event, notification)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("failed to join notify thread"))
+            .collect()
+    };
+    status.dispatch_time = dispatch_started.elapsed();
+
+    let mut errors = Vec::new();
+    for result in results {
+        match result {
+            Ok((event, action)) => {
+                status.notified += 1;
+                if dedup {
+                    // Without a window, like a dismissed event it's kept until khal stops listing it.
+                    let until = match dedup_window {
+                        Some(window) => Some(OffsetDateTime::now_utc() + window),
+                        None => event
+                            .listed_until()
+                            .map(|until| until + since.unwrap_or_default()),
+                    };
+                    if let Some(until) = until {
+                        state.notified(&event, until);
+                        state_changed = true;
+                    }
+                }
+                if action.as_deref() == Some(notify::DISMISS_ACTION) {
+                    state.dismiss(&event);
+                    state_changed = true;
+                }
+                let chosen_link = action
+                    .as_deref()
+                    .and_then(|action| notifier.chosen_link(&event, action));
+                if let Some(link) = chosen_link {
+                    if let Err(err) = opener::open(link) {
+                        errors.push(format!("could not open {}: {}", link, err));
+                    }
                 }
-                short_desc += &event.start_end_time_style;
             }
+            Err(err) => errors.push(err),
+        }
+    }
+    if state_changed {
+        if let Err(err) = state.save(state_file) {
+            errors.push(err);
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
 
-            Command::new("notify-send")
-                .args(&[title, short_desc])
-                .spawn()
-                .expect("could not create notification")
-                .wait()
-                .expect("notification process ended unexpectedly");
-        });
-        handles.push(handle);
+/// Sends the notification for `event`, recording it in the audit log if there is one, and
+/// returns the event along with the action chosen for it.
+fn deliver(
+    notifier: &Notifier,
+    audit_log: Option<&AuditLog>,
+    event: KhalEvent,
+    notification: Notification,
+) -> Result<(KhalEvent, Option<String>), String> {
+    // Returns before recording anything if the notify command failed, so the log only holds
+    // notifications that were shown.
+    let action = notifier.deliver(&event, &notification.title, &notification.body)?;
+    if let Some(audit_log) = audit_log {
+        // The notification did go out, so this doesn't fail it.
+        if let Err(err) = audit_log.record(&event, &notification) {
+            eprintln!("warning: {}", err);
+        }
     }
-    handles
-        .into_iter()
-        .for_each(|handle| handle.join().expect("failed to join notify thread"));
+    Ok((event, action))
 }
 
-fn find_links(url_regex: Arc<Regex>, rem_desc: String) -> Vec<String> {
-    let urls: Vec<_> = url_regex.captures_iter(&rem_desc).collect();
-    let mut url_matches: Vec<_> = urls
-        .iter()
-        .map(|cap| cap.get(0))
-        .flatten()
-        .map(|url| url.as_str())
-        .collect();
-    url_matches.sort_unstable();
-    url_matches.dedup();
-    url_matches
-        .iter()
-        .map(|url| format!("<a href=\"{}\"></a>", url))
-        .collect()
+/// Replaces `\n` in `text` with a newline and `\\` with a backslash, leaving other backslashes
+/// alone.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Parses the number given for the argument `name`.
+fn parse_number<T: FromStr>(value: &str, name: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} is not a number: {:?}", name, value))
+}
+
+/// Parses an `HH:MM` time of day given for `option`.
+fn parse_clock(value: Option<&str>, option: &str) -> Result<Option<Time>, String> {
+    value
+        .map(|value| {
+            Time::parse(value, "%R")
+                .map_err(|err| format!("could not parse {} {:?}: {}", option, value, err))
+        })
+        .transpose()
+}
+
+/// Whether `time` is at or after `after` and before `before`, where a window with `after` later
+/// than `before` runs overnight.
+fn in_clock_window(time: Time, after: Option<Time>, before: Option<Time>) -> bool {
+    match (after, before) {
+        (Some(after), Some(before)) if after > before => time >= after || time < before,
+        _ => after.is_none_or(|after| time >= after) && before.is_none_or(|before| time < before),
+    }
+}
+
+/// Whether an event starting `lead` from now is no sooner than `min_lead` and no later than
+/// `max_lead`.
+fn in_lead_window(
+    lead: time::Duration,
+    min_lead: Option<time::Duration>,
+    max_lead: Option<time::Duration>,
+) -> bool {
+    min_lead.is_none_or(|min| lead >= min) && max_lead.is_none_or(|max| lead <= max)
+}
+
+/// When the `i`th notification is due to be sent, counting from the start of dispatch, with
+/// `spacing` between sends and `batch_delay` more after every `batch_size` of them.
+fn send_due(i: usize, spacing: Duration, batch_size: usize, batch_delay: Duration) -> Duration {
+    // Saturating, as a small enough rate limit leaves sends too far apart to count.
+    spacing
+        .saturating_mul(i as u32)
+        .saturating_add(batch_delay.saturating_mul((i / batch_size) as u32))
+}
+
+/// Like `retain`, but keeping the events left out in `filtered_out`, along with `reason`.
+fn filter(
+    events: &mut Vec<KhalEvent>,
+    filtered_out: &mut Vec<FilteredOut>,
+    reason: &'static str,
+    mut keep: impl FnMut(&KhalEvent) -> bool,
+) {
+    let (kept, left_out): (Vec<_>, Vec<_>) = events.drain(..).partition(|event| keep(event));
+    *events = kept;
+    filtered_out.extend(
+        left_out
+            .into_iter()
+            .map(|event| FilteredOut { reason, event }),
+    );
+}
+
+fn fail(err: impl fmt::Display) -> ! {
+    eprintln!("{}", err);
+    process::exit(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lead_window() {
+        let (min, max) = (Some(10.minutes()), Some(1.hours()));
+        assert!(in_lead_window(10.minutes(), min, max));
+        assert!(in_lead_window(1.hours(), min, max));
+        assert!(!in_lead_window(5.minutes(), min, max));
+        assert!(!in_lead_window(2.hours(), min, max));
+        assert!(in_lead_window(2.hours(), min, None));
+        assert!(in_lead_window((-5).minutes(), None, max));
+    }
+
+    #[test]
+    fn all_day_event_lead_is_from_midnight() {
+        let now = OffsetDateTime::now_utc();
+        let all_day = |date: time::Date| {
+            let mut event = KhalEvent {
+                start_date: date.format("%F"),
+                end_date: date.format("%F"),
+                all_day: true,
+                ..KhalEvent::default()
+            };
+            event.resolve_times("%F", "%R", UtcOffset::UTC);
+            event.start.unwrap() - now
+        };
+        let tomorrow = all_day(now.date() + 1.days());
+        assert!(in_lead_window(
+            tomorrow,
+            Some(0.minutes()),
+            Some(24.hours())
+        ));
+        assert!(!in_lead_window(
+            tomorrow,
+            None,
+            Some(tomorrow - 1.minutes())
+        ));
+        let today = all_day(now.date());
+        assert!(!in_lead_window(today, Some(0.minutes()), None));
+        assert!(in_lead_window(today, None, Some(0.minutes())));
+    }
+
+    #[test]
+    fn sends_are_spaced_by_rate_limit() {
+        let spacing = Duration::from_millis(500);
+        let due: Vec<Duration> = (0..4)
+            .map(|i| send_due(i, spacing, usize::MAX, Duration::ZERO))
+            .collect();
+        assert_eq!(due, [0, 500, 1000, 1500].map(Duration::from_millis));
+        assert_eq!(
+            send_due(3, Duration::ZERO, usize::MAX, Duration::ZERO),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn batches_wait_for_batch_delay() {
+        let spacing = Duration::from_millis(100);
+        let delay = Duration::from_secs(5);
+        let due: Vec<Duration> = (0..7).map(|i| send_due(i, spacing, 3, delay)).collect();
+        assert_eq!(
+            due,
+            [0, 100, 200, 5300, 5400, 5500, 10600].map(Duration::from_millis)
+        );
+        // Every notification gets a time, including those of a last, partly filled batch.
+        assert!(due.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn sends_far_apart_saturate() {
+        let spacing = Duration::from_secs(u64::MAX / 2);
+        assert_eq!(
+            send_due(3, spacing, usize::MAX, Duration::ZERO),
+            Duration::MAX
+        );
+        assert_eq!(send_due(3, spacing, 1, spacing), Duration::MAX);
+    }
 }
@@ -0,0 +1,631 @@
+use crate::icon::Icons;
+use crate::khal::KhalEvent;
+use crate::locale::{Locale, Phrase};
+use crate::{desc, shell, template, which};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_COMMAND: &str = "notify-send";
+/// Linux refuses single arguments longer than this, `MAX_ARG_STRLEN` counting the terminating NUL.
+pub const MAX_ARG_LENGTH: &str = "131071";
+
+/// Name of the action added by `--dismiss-action`, as printed by the notify command when chosen.
+pub const DISMISS_ACTION: &str = "dismiss";
+/// Starts the names of the actions added by `--link-actions`, which go on with the number of the
+/// link, counting from 1 in the order the links appear in the description.
+const LINK_ACTION_PREFIX: &str = "open-";
+
+/// Set once the notify command has failed when given link actions, taking it as one that doesn't
+/// support actions, so that they're left off from then on.
+static LINK_ACTIONS_REJECTED: AtomicBool = AtomicBool::new(false);
+
+/// Prefixed to the command run over ssh, pointing it at the remote user's desktop session unless
+/// the remote environment already says where that is.
+const REMOTE_ENV: &str = "DISPLAY=${DISPLAY:-:0} \
+    DBUS_SESSION_BUS_ADDRESS=${DBUS_SESSION_BUS_ADDRESS:-unix:path=/run/user/$(id -u)/bus}";
+
+/// Keeps `--repeat-critical` from holding the process open for too long.
+const MAX_REPEAT_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// How often a notify command with a timeout is checked on.
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Process ids of notify commands that haven't finished yet.
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Notify commands sent with `--no-notify-send-wait`, which are reaped later instead.
+static DETACHED: Mutex<Vec<Child>> = Mutex::new(Vec::new());
+
+/// Reaps the detached notify commands that have exited, without waiting for the rest.
+pub fn reap_detached() {
+    DETACHED.lock().unwrap().retain_mut(|child| {
+        let running = matches!(child.try_wait(), Ok(None));
+        if !running {
+            unregister(child.id());
+        }
+        running
+    });
+}
+
+/// Waits for every detached notify command to exit.
+pub fn wait_detached() {
+    for mut child in DETACHED.lock().unwrap().drain(..) {
+        let _ = child.wait();
+        unregister(child.id());
+    }
+}
+
+fn unregister(pid: u32) {
+    RUNNING.lock().unwrap().retain(|&running| running != pid);
+}
+
+/// Asks every notify command still running to terminate.
+pub fn kill_running() {
+    for &pid in RUNNING.lock().unwrap().iter() {
+        // Children are dropped from the list as soon as they are reaped, so the pid is still ours.
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+}
+
+/// Notification urgency levels, as notify-send names them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    pub fn name(self) -> &'static str {
+        match self {
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for Urgency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Urgency::Low),
+            "normal" => Ok(Urgency::Normal),
+            "critical" => Ok(Urgency::Critical),
+            _ => Err(format!("unknown urgency {:?}", s)),
+        }
+    }
+}
+
+/// Parses `--calendar-urgency`'s comma-separated `CALENDAR=URGENCY` pairs.
+pub fn parse_calendar_urgency(mapping: &str) -> Result<HashMap<String, Urgency>, String> {
+    mapping
+        .split(',')
+        .map(|pair| {
+            let (calendar, urgency) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected CALENDAR=URGENCY, got {:?}", pair))?;
+            Ok((calendar.to_owned(), urgency.parse()?))
+        })
+        .collect()
+}
+
+/// How `--repeat-critical` resends notifications.
+#[derive(Debug, Clone, Copy)]
+pub struct Repeat {
+    pub count: u32,
+    pub interval: Duration,
+}
+
+impl FromStr for Repeat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected COUNT:SECONDS, got {:?}", s);
+        let (count, seconds) = s.split_once(':').ok_or_else(invalid)?;
+        let repeat = Repeat {
+            count: count.parse().map_err(|_| invalid())?,
+            interval: Duration::from_secs(seconds.parse().map_err(|_| invalid())?),
+        };
+        let total = repeat.interval.checked_mul(repeat.count);
+        if total.is_none_or(|total| total > MAX_REPEAT_DURATION) {
+            return Err(format!(
+                "repeating {:?} would take longer than {} minutes",
+                s,
+                MAX_REPEAT_DURATION.as_secs() / 60
+            ));
+        }
+        Ok(repeat)
+    }
+}
+
+/// What the id a notification replaces earlier ones by is made from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplaceKey {
+    /// This occurrence of the event, so only its own repeats are replaced.
+    Occurrence,
+    Title,
+    /// Every occurrence of a recurring event.
+    Uid,
+    /// The same id for every notification, so each replaces the last.
+    Fixed(String),
+}
+
+impl ReplaceKey {
+    /// The id passed to the daemon for `event`.
+    pub fn id(&self, event: &KhalEvent) -> String {
+        match self {
+            ReplaceKey::Occurrence => event.id(),
+            ReplaceKey::Title => event.title.clone(),
+            ReplaceKey::Uid if event.uid.is_empty() => event.title.clone(),
+            ReplaceKey::Uid => event.uid.clone(),
+            ReplaceKey::Fixed(id) => id.clone(),
+        }
+    }
+}
+
+impl FromStr for ReplaceKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "title" => Ok(ReplaceKey::Title),
+            "uid" => Ok(ReplaceKey::Uid),
+            _ => match s.strip_prefix("fixed:") {
+                Some(id) if !id.is_empty() => Ok(ReplaceKey::Fixed(id.to_owned())),
+                _ => Err(format!("expected title, uid or fixed:ID, got {:?}", s)),
+            },
+        }
+    }
+}
+
+/// How notifications are handed off to the notification daemon.
+pub struct Notifier {
+    pub command: String,
+    /// Used instead of `command` for critical events.
+    pub urgent_command: Option<String>,
+    /// Runs the command over ssh on this host instead of locally.
+    pub host: Option<String>,
+    /// Passed before the title and body, with `{field}` placeholders filled in from each event.
+    pub args: Vec<String>,
+    /// Urgency passed for events from these calendars, leaving the rest to the daemon's default.
+    pub calendar_urgency: HashMap<String, Urgency>,
+    /// Pass an icon chosen from what kind of event it is.
+    pub icons: Option<Icons>,
+    /// How long notifications stay up before the daemon closes them, where zero is until they're
+    /// closed by hand.
+    pub expire_time: Option<Duration>,
+    /// Used instead of `expire_time` for notifications of these urgencies, taking events without
+    /// one as normal.
+    pub urgency_expire_time: HashMap<Urgency, Duration>,
+    /// Resend critical notifications this many more times.
+    pub repeat_critical: Option<Repeat>,
+    /// Have the daemon replace earlier notifications with the same key instead of adding another.
+    pub replace: Option<ReplaceKey>,
+    /// Passed as a `thread-id` hint, so that daemons which support it group the notifications.
+    pub thread_id: Option<String>,
+    /// Offer a Dismiss action, waiting for the notify command to print which action was chosen.
+    pub dismiss_action: bool,
+    /// Offer an action for opening each of up to this many links from the event's description.
+    pub link_actions: usize,
+    /// Leave notify commands running and move on, so that errors from them go unnoticed.
+    pub detach: bool,
+    /// Write the body to the command's stdin rather than passing it as an argument.
+    pub body_stdin: bool,
+    /// Which language the Dismiss button is labelled in.
+    pub locale: Locale,
+    /// Print notifications to stderr if the notify command can't be found, rather than failing.
+    pub stderr_fallback: bool,
+    /// Kill notify commands that haven't exited after this long.
+    pub timeout: Option<Duration>,
+    /// Bodies passed as an argument are cut to this many bytes so the notify command can run.
+    pub max_arg_length: usize,
+}
+
+impl Notifier {
+    /// Sends the notification for `event`, then again as many times as `repeat_critical` says if
+    /// the event is critical, stopping early if it's dismissed.
+    pub fn deliver(
+        &self,
+        event: &KhalEvent,
+        title: &str,
+        body: &str,
+    ) -> Result<Option<String>, String> {
+        let mut action = self.send(event, title, body)?;
+        if let Some(repeat) = self.repeat_critical {
+            if self.urgency(event) == Some(Urgency::Critical) {
+                for _ in 0..repeat.count {
+                    if action.as_deref() == Some(DISMISS_ACTION) {
+                        break;
+                    }
+                    thread::sleep(repeat.interval);
+                    action = self.send(event, title, body)?;
+                }
+            }
+        }
+        Ok(action)
+    }
+
+    /// The first of the commands notifications may be sent with that isn't there to run.
+    pub fn missing_command(&self) -> Option<String> {
+        let programs = match &self.host {
+            Some(_) => vec!["ssh"],
+            None => std::iter::once(&self.command)
+                .chain(&self.urgent_command)
+                .map(String::as_str)
+                .collect(),
+        };
+        programs
+            .into_iter()
+            .find_map(|program| which::find(program).err())
+    }
+
+    /// The links from `event`'s description offered as actions.
+    fn action_links<'a>(&self, event: &'a KhalEvent) -> Vec<&'a str> {
+        if LINK_ACTIONS_REJECTED.load(Ordering::Relaxed) {
+            return Vec::new();
+        }
+        let mut links = desc::find_links(&event.description, false);
+        links.truncate(self.link_actions);
+        links
+    }
+
+    /// The link from `event`'s description that `action` opens, if it's one of the link actions.
+    pub fn chosen_link<'a>(&self, event: &'a KhalEvent, action: &str) -> Option<&'a str> {
+        let number: usize = action.strip_prefix(LINK_ACTION_PREFIX)?.parse().ok()?;
+        desc::find_links(&event.description, false)
+            .get(number.checked_sub(1)?)
+            .copied()
+    }
+
+    /// The urgency passed for `event`, if its calendar has one.
+    pub fn urgency(&self, event: &KhalEvent) -> Option<Urgency> {
+        self.calendar_urgency.get(&event.calendar).copied()
+    }
+
+    /// The program and arguments `send` runs for `event`, which is `ssh` with the notify command
+    /// as its last argument when sending to another host.
+    pub fn command_line(&self, event: &KhalEvent, title: &str, body: &str) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| template::expand(arg, |name| event.field(name)))
+            .collect();
+        let urgency = self.urgency(event);
+        let program = match (&self.urgent_command, urgency) {
+            (Some(urgent_command), Some(Urgency::Critical)) => urgent_command,
+            _ => &self.command,
+        };
+        if let Some(urgency) = urgency {
+            args.push(format!("--urgency={}", urgency.name()));
+        }
+        let expire_time = self
+            .urgency_expire_time
+            .get(&urgency.unwrap_or(Urgency::Normal))
+            .or(self.expire_time.as_ref());
+        if let Some(expire_time) = expire_time {
+            args.push(format!("--expire-time={}", expire_time.as_millis()));
+        }
+        if let Some(icon) = self.icons.as_ref().and_then(|icons| icons.for_event(event)) {
+            args.push(format!("--icon={}", icon));
+        }
+        if let Some(key) = &self.replace {
+            args.push(format!(
+                "--hint=string:x-canonical-private-synchronous:{}",
+                key.id(event)
+            ));
+        }
+        if let Some(thread_id) = &self.thread_id {
+            args.push(format!("--hint=string:thread-id:{}", thread_id));
+        }
+        if self.dismiss_action {
+            args.push(format!(
+                "--action={}={}",
+                DISMISS_ACTION,
+                self.locale.text(Phrase::Dismiss)
+            ));
+        }
+        for (i, link) in self.action_links(event).iter().enumerate() {
+            args.push(format!(
+                "--action={}{}={} {}",
+                LINK_ACTION_PREFIX,
+                i + 1,
+                self.locale.text(Phrase::Open),
+                desc::host(link)
+            ));
+        }
+        args.push(title.to_owned());
+        if !self.body_stdin {
+            args.push(self.body_arg(title, body).to_owned());
+        }
+
+        match &self.host {
+            Some(host) => {
+                let remote: String = std::iter::once(REMOTE_ENV.into())
+                    .chain(std::iter::once(shell::quote(program)))
+                    .chain(args.iter().map(|arg| shell::quote(arg)))
+                    .intersperse(" ".into())
+                    .collect();
+                vec!["ssh".to_owned(), host.clone(), "--".to_owned(), remote]
+            }
+            None => std::iter::once(program.clone()).chain(args).collect(),
+        }
+    }
+
+    /// Sends a single notification, waiting for the notify command to finish.
+    ///
+    /// Returns the action that was chosen, if actions were offered and the command printed one.
+    pub fn send(
+        &self,
+        event: &KhalEvent,
+        title: &str,
+        body: &str,
+    ) -> Result<Option<String>, String> {
+        let command_line = self.command_line(event, title, body);
+        let link_actions = !self.action_links(event).is_empty();
+        let mut command = Command::new(&command_line[0]);
+        command.args(&command_line[1..]);
+        if self.body_stdin {
+            command.stdin(Stdio::piped());
+        }
+        if self.dismiss_action || link_actions {
+            command.stdout(Stdio::piped());
+        }
+        // Kept to explain a failure, unless nothing will be waiting to read it.
+        if !self.detach {
+            command.stderr(Stdio::piped());
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && self.stderr_fallback => {
+                eprint!("{}\n{}\n\n", title, body);
+                return Ok(None);
+            }
+            Err(err) => {
+                return Err(format!(
+                    "could not create notification for {:?}: {}",
+                    title, err
+                ))
+            }
+        };
+        RUNNING.lock().unwrap().push(child.id());
+        let written = match child.stdin.take() {
+            Some(mut stdin) => stdin.write_all(body.as_bytes()).map_err(|err| {
+                format!("could not write notification body for {:?}: {}", title, err)
+            }),
+            None => Ok(()),
+        };
+        if self.detach {
+            DETACHED.lock().unwrap().push(child);
+            return written.map(|_| None);
+        }
+        // Read as it comes, so that a command writing a lot to it can't fill the pipe and hang.
+        let stderr = child.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut text = String::new();
+                // What's unreadable is just left out of the message.
+                let _ = stderr.read_to_string(&mut text);
+                text
+            })
+        });
+        let waited = self.wait(&mut child, title);
+        unregister(child.id());
+        let status = written.and(waited)?;
+        // Not waited for after a timeout, as whatever the command started may still hold it open.
+        let stderr = stderr
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default();
+        if link_actions && !status.success() {
+            // The links are still in the body, which is all there is without actions.
+            if !LINK_ACTIONS_REJECTED.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "warning: {} failed with link actions, sending without them from now on",
+                    command_line[0]
+                );
+            }
+            return self.send(event, title, body);
+        }
+        if !status.success() {
+            let mut message = match status.code() {
+                Some(code) => format!(
+                    "notification for {:?} failed: {} exited with code {}",
+                    title, command_line[0], code
+                ),
+                None => format!(
+                    "notification for {:?} failed: {} was terminated by a signal",
+                    title, command_line[0]
+                ),
+            };
+            if !stderr.trim().is_empty() {
+                message += &format!("\n{} stderr: {}", command_line[0], stderr.trim());
+            }
+            return Err(message);
+        }
+        // Passed on as it would have been had it not been captured.
+        eprint!("{}", stderr);
+        // Read after the command exits, so that one that hangs can still be timed out.
+        let mut action = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            // Anything unreadable just means no action was chosen.
+            let _ = stdout.read_to_string(&mut action);
+        }
+        let action = action.trim();
+        Ok(if action.is_empty() {
+            None
+        } else {
+            Some(action.to_owned())
+        })
+    }
+
+    /// Waits for the notify command to exit, killing it if it takes longer than the timeout.
+    fn wait(&self, child: &mut Child, title: &str) -> Result<ExitStatus, String> {
+        let ended = |err| {
+            format!(
+                "notification process for {:?} ended unexpectedly: {}",
+                title, err
+            )
+        };
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return child.wait().map_err(ended),
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait().map_err(ended)? {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "notification process for {:?} was killed after {} seconds",
+                    title,
+                    timeout.as_secs()
+                ));
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+    }
+
+    /// The body as it can be passed on the command line, truncated if it's too long to.
+    fn body_arg<'a>(&self, title: &str, body: &'a str) -> &'a str {
+        if body.len() <= self.max_arg_length {
+            return body;
+        }
+        eprintln!(
+            "warning: body of {:?} is {} bytes, truncating to {} (see --body-stdin)",
+            title,
+            body.len(),
+            self.max_arg_length
+        );
+        truncate_markup(body, self.max_arg_length)
+    }
+}
+
+/// Cuts `text` to at most `max` bytes without splitting a character, or a Pango tag or entity
+/// that would be left unterminated and make the whole body fail to parse.
+fn truncate_markup(text: &str, max: usize) -> &str {
+    let mut end = max.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut cut = &text[..end];
+    if end == text.len() {
+        return cut;
+    }
+    if let Some(open) = cut.rfind('<') {
+        if cut[open..].find('>').is_none() {
+            cut = &cut[..open];
+        }
+    }
+    if let Some(amp) = cut.rfind('&') {
+        if cut[amp + 1..]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '#')
+        {
+            cut = &cut[..amp];
+        }
+    }
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncation_keeps_markup_whole() {
+        assert_eq!(truncate_markup("héllo", 2), "h");
+        assert_eq!(truncate_markup("hello", 10), "hello");
+        assert_eq!(truncate_markup("see <b>this</b>", 10), "see <b>thi");
+        assert_eq!(
+            truncate_markup("see <a href=\"https://example.com\">x</a>", 20),
+            "see "
+        );
+        assert_eq!(truncate_markup("Q&amp;A &lt;3", 4), "Q");
+        assert_eq!(truncate_markup("Q&amp;A &#60;3", 11), "Q&amp;A ");
+        assert_eq!(truncate_markup("Q&amp;A later", 9), "Q&amp;A l");
+        // A cut that lands right after a whole tag or entity keeps it.
+        assert_eq!(truncate_markup("<i>a</i> b", 8), "<i>a</i>");
+        assert_eq!(truncate_markup("a &amp; b", 7), "a &amp;");
+    }
+
+    fn event(title: &str, uid: &str, start_time: &str) -> KhalEvent {
+        KhalEvent {
+            title: title.to_owned(),
+            uid: uid.to_owned(),
+            start_date: "2024-01-02".to_owned(),
+            start_time: start_time.to_owned(),
+            ..KhalEvent::default()
+        }
+    }
+
+    #[test]
+    fn replace_key_from_str() {
+        assert_eq!("title".parse(), Ok(ReplaceKey::Title));
+        assert_eq!("uid".parse(), Ok(ReplaceKey::Uid));
+        assert_eq!(
+            "fixed:agenda".parse(),
+            Ok(ReplaceKey::Fixed("agenda".to_owned()))
+        );
+        assert!("fixed:".parse::<ReplaceKey>().is_err());
+        assert!("start".parse::<ReplaceKey>().is_err());
+    }
+
+    #[test]
+    fn same_key_gives_same_id() {
+        let first = event("Standup", "abc@example.com", "09:00");
+        let repeat = event("Standup", "abc@example.com", "10:00");
+        let other = event("Standup", "def@example.com", "09:00");
+
+        let title = ReplaceKey::Title;
+        assert_eq!(title.id(&first), title.id(&repeat));
+        assert_eq!(title.id(&first), title.id(&other));
+
+        let uid = ReplaceKey::Uid;
+        assert_eq!(uid.id(&first), uid.id(&repeat));
+        assert_ne!(uid.id(&first), uid.id(&other));
+
+        let fixed = ReplaceKey::Fixed("agenda".to_owned());
+        assert_eq!(fixed.id(&first), "agenda");
+        assert_eq!(fixed.id(&other), "agenda");
+    }
+
+    #[test]
+    fn uid_key_falls_back_to_title() {
+        let uid = ReplaceKey::Uid;
+        let first = event("Standup", "", "09:00");
+        let repeat = event("Standup", "", "10:00");
+        assert_eq!(uid.id(&first), "Standup");
+        assert_eq!(uid.id(&first), uid.id(&repeat));
+        assert_ne!(uid.id(&first), uid.id(&event("Review", "", "09:00")));
+    }
+
+    #[test]
+    fn repeat_from_str() {
+        let repeat: Repeat = "3:60".parse().unwrap();
+        assert_eq!(repeat.count, 3);
+        assert_eq!(repeat.interval, Duration::from_secs(60));
+        assert!("3".parse::<Repeat>().is_err());
+        assert!("x:60".parse::<Repeat>().is_err());
+    }
+
+    #[test]
+    fn repeat_longer_than_an_hour_is_rejected() {
+        assert!("60:60".parse::<Repeat>().is_ok());
+        for repeat in &["61:60", "4294967295:18446744073709551615"] {
+            let err = repeat.parse::<Repeat>().unwrap_err();
+            assert!(err.contains("longer than 60 minutes"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::khal::KhalEvent;
+use crate::locale::{Locale, Phrase};
+use crate::render::Notification;
+use indexmap::IndexMap;
+use serde::{Serialize, Serializer};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::time::Duration;
+use time::Format;
+use unicode_width::UnicodeWidthStr;
+
+/// How long a socket reader that stops reading can hold up a run.
+const SOCKET_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where rendered notifications go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Notify,
+    /// A JSON object of every notification, along with the events filtered out and what was
+    /// looked for.
+    Json,
+    /// A single JSON array of every notification.
+    JsonCompact,
+    /// One JSON object per line, per notification.
+    Jsonl,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notify" => Ok(Mode::Notify),
+            "json" => Ok(Mode::Json),
+            "json-compact" => Ok(Mode::JsonCompact),
+            "jsonl" => Ok(Mode::Jsonl),
+            _ => Err(format!("unknown output mode {:?}", s)),
+        }
+    }
+}
+
+/// The version of the JSON that `--output` and `--output-socket` write, given as
+/// `schema_version` in `--output json`. It's raised whenever a field is removed, renamed or
+/// changes meaning, but not for added fields. `schema/output-v1.json` describes this version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A rendered notification along with the event it came from.
+#[derive(Debug)]
+pub struct Record<'a> {
+    pub notification: &'a Notification,
+    pub event: &'a KhalEvent,
+}
+
+impl Serialize for Record<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RecordJson {
+            title: &self.notification.title,
+            body: &self.notification.body,
+            event: EventJson::from(self.event),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// An event khal returned that was left out, and which filter left it out.
+#[derive(Debug)]
+pub struct FilteredOut {
+    pub reason: &'static str,
+    pub event: KhalEvent,
+}
+
+impl Serialize for FilteredOut {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        FilteredOutJson {
+            reason: self.reason,
+            event: EventJson::from(&self.event),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// What a run looked for and how much it found.
+#[derive(Serialize, Debug)]
+pub struct Meta {
+    /// The times checked, in RFC 3339, with a range given as `START/END`.
+    pub targets: Vec<String>,
+    /// The UTC offset times were read in.
+    pub timezone: String,
+    /// Events khal returned.
+    pub returned: usize,
+    pub matched: usize,
+    pub filtered_out: usize,
+}
+
+/// What `--output json` adds to the notifications.
+#[derive(Debug)]
+pub struct Diagnostics {
+    pub filtered_out: Vec<FilteredOut>,
+    pub meta: Meta,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    schema_version: u32,
+    events: &'a [Record<'a>],
+    filtered_out: &'a [FilteredOut],
+    meta: &'a Meta,
+}
+
+// The structs below spell out the output format, so that it stays the same whatever happens to
+// `KhalEvent`, which only needs to match what khal prints.
+
+#[derive(Serialize)]
+struct RecordJson<'a> {
+    title: &'a str,
+    body: &'a str,
+    event: EventJson<'a>,
+}
+
+#[derive(Serialize)]
+struct FilteredOutJson<'a> {
+    reason: &'a str,
+    event: EventJson<'a>,
+}
+
+#[derive(Serialize)]
+struct EventJson<'a> {
+    title: &'a str,
+    description: &'a str,
+    location: &'a str,
+    uid: &'a str,
+    calendar: &'a str,
+    #[serde(rename = "start-date")]
+    start_date: &'a str,
+    #[serde(rename = "start-time")]
+    start_time: &'a str,
+    #[serde(rename = "end-date")]
+    end_date: &'a str,
+    #[serde(rename = "end-time")]
+    end_time: &'a str,
+    #[serde(rename = "start-end-time-style")]
+    start_end_time_style: &'a str,
+    #[serde(rename = "repeat-symbol")]
+    repeat_symbol: &'a str,
+    #[serde(rename = "all-day")]
+    all_day: bool,
+    attendees: &'a [String],
+    organizer: &'a str,
+    /// Fields from `--json-fields-extra`, under the names khal gave them.
+    #[serde(flatten)]
+    extra: &'a IndexMap<String, String>,
+    start: Option<String>,
+    end: Option<String>,
+    started: bool,
+}
+
+impl<'a> From<&'a KhalEvent> for EventJson<'a> {
+    fn from(event: &'a KhalEvent) -> Self {
+        EventJson {
+            title: &event.title,
+            description: &event.description,
+            location: &event.location,
+            uid: &event.uid,
+            calendar: &event.calendar,
+            start_date: &event.start_date,
+            start_time: &event.start_time,
+            end_date: &event.end_date,
+            end_time: &event.end_time,
+            start_end_time_style: &event.start_end_time_style,
+            repeat_symbol: &event.repeat_symbol,
+            all_day: event.all_day,
+            attendees: &event.attendees,
+            organizer: &event.organizer,
+            extra: &event.extra,
+            start: event.start.map(|start| start.format(Format::Rfc3339)),
+            end: event.end.map(|end| end.format(Format::Rfc3339)),
+            started: event.started,
+        }
+    }
+}
+
+/// Prints `records` to stdout in one of the JSON modes.
+pub fn print(mode: Mode, records: &[Record], diagnostics: &Diagnostics) -> Result<(), String> {
+    match mode {
+        Mode::Notify => {}
+        Mode::Json => {
+            let document = Document {
+                schema_version: SCHEMA_VERSION,
+                events: records,
+                filtered_out: &diagnostics.filtered_out,
+                meta: &diagnostics.meta,
+            };
+            let json = serde_json::to_string_pretty(&document).map_err(|err| err.to_string())?;
+            println!("{}", json);
+        }
+        Mode::JsonCompact => {
+            let json = serde_json::to_string_pretty(records).map_err(|err| err.to_string())?;
+            println!("{}", json);
+        }
+        Mode::Jsonl => {
+            for record in records {
+                let json = serde_json::to_string(record).map_err(|err| err.to_string())?;
+                println!("{}", json);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `records` as JSON lines to the Unix socket or named pipe at `path`.
+///
+/// Nothing listening, or a pipe with no reader, isn't an error, as the widgets reading from it
+/// come and go, so a note is printed and the records are dropped.
+pub fn write_socket(path: &str, records: &[Record]) -> Result<(), String> {
+    let mut lines = String::new();
+    for record in records {
+        lines += &serde_json::to_string(record).map_err(|err| err.to_string())?;
+        lines.push('\n');
+    }
+    let is_fifo = fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo());
+    let written = if is_fifo {
+        // Opening a pipe for writing blocks until there's a reader, unless it's non-blocking,
+        // in which case it fails straight away.
+        OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .and_then(|mut pipe| pipe.write_all(lines.as_bytes()))
+    } else {
+        UnixStream::connect(path).and_then(|mut socket| {
+            socket.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT))?;
+            socket.write_all(lines.as_bytes())
+        })
+    };
+    match written {
+        Ok(()) => Ok(()),
+        Err(err) if no_reader(&err) => {
+            eprintln!(
+                "note: nothing is reading from {}, skipping {} events",
+                path,
+                records.len()
+            );
+            Ok(())
+        }
+        Err(err) => Err(format!("could not write events to {}: {}", path, err)),
+    }
+}
+
+fn no_reader(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    ) || err.raw_os_error() == Some(libc::ENXIO)
+}
+
+/// Lays `events` out as the lines of a plain table of their date, time, title and calendar,
+/// padded by display width so that wide characters line up.
+pub fn table(events: &[KhalEvent], locale: Locale) -> Vec<String> {
+    let rows: Vec<[String; 4]> = events
+        .iter()
+        .map(|event| {
+            let mut title = event.title.replace('\n', " ");
+            if !event.repeat_symbol.is_empty() {
+                title += " ";
+                title += &event.repeat_symbol;
+            }
+            [
+                event.start_date.clone(),
+                if event.all_day {
+                    locale.text(Phrase::AllDay).to_owned()
+                } else {
+                    event.start_end_time_style.clone()
+                },
+                title,
+                event.calendar.clone(),
+            ]
+        })
+        .collect();
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.width());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (i, cell) in row.iter().enumerate() {
+                line += cell;
+                // The last column isn't padded, so lines don't end in spaces.
+                if i + 1 < row.len() {
+                    line += &" ".repeat(widths[i] - cell.width() + 2);
+                }
+            }
+            line.trim_end().to_owned()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extra_fields_keep_khal_order() {
+        let event: KhalEvent = serde_json::from_str(
+            r#"{"title": "", "description": "", "location": "", "uid": "", "calendar": "",
+            "start-date": "", "start-time": "", "end-date": "", "end-time": "",
+            "repeat-symbol": "", "all-day": false,
+            "zeta": "1", "alpha": "2", "mid": "3", "beta": "4"}"#,
+        )
+        .unwrap();
+        let keys: Vec<&str> = event.extra.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["zeta", "alpha", "mid", "beta"]);
+
+        let json = serde_json::to_string(&EventJson::from(&event)).unwrap();
+        let positions: Vec<usize> = keys
+            .iter()
+            .map(|key| json.find(&format!("\"{}\":", key)).unwrap())
+            .collect();
+        assert!(
+            positions.windows(2).all(|pair| pair[0] < pair[1]),
+            "{}",
+            json
+        );
+    }
+
+    #[test]
+    fn table_aligns_wide_titles() {
+        let event = |title: &str, calendar: &str| KhalEvent {
+            title: title.to_owned(),
+            calendar: calendar.to_owned(),
+            start_date: "2024-01-02".to_owned(),
+            start_end_time_style: "09:00-10:00".to_owned(),
+            ..KhalEvent::default()
+        };
+        let events = [
+            event("会議", "work"),
+            event("Standup", "work"),
+            event("打ち合わせ準備", "home"),
+        ];
+        let lines = table(&events, Locale::default());
+        assert_eq!(
+            lines,
+            [
+                "2024-01-02  09:00-10:00  会議            work",
+                "2024-01-02  09:00-10:00  Standup         work",
+                "2024-01-02  09:00-10:00  打ち合わせ準備  home",
+            ]
+        );
+        let calendar_columns: Vec<usize> = lines
+            .iter()
+            .map(|line| line[..line.rfind(' ').unwrap()].width())
+            .collect();
+        assert!(calendar_columns
+            .iter()
+            .all(|&column| column == calendar_columns[0]));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::notify::Urgency;
+use crate::render::Notification;
+use regex::Regex;
+use std::fs;
+use std::sync::LazyLock;
+
+/// The anchors `--markup pango` puts in bodies, which are the only markup kept in a preview.
+static ANCHOR_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<a href="([^"]*)">(.*?)</a>"#).unwrap());
+
+const STYLE: &str = "body { font-family: sans-serif; background: #eee; max-width: 40em; margin: 2em auto; }
+.notification { background: #fff; border-left: 6px solid; border-radius: 4px; padding: 0.5em 1em; margin-bottom: 1em; }
+.low { border-color: #999; }
+.normal { border-color: #3584e4; }
+.critical { border-color: #e01b24; }
+.title { font-weight: bold; margin: 0.3em 0; }
+.body { white-space: pre-wrap; margin: 0.3em 0; }
+.urgency { color: #777; font-size: smaller; }";
+
+/// Writes `notifications` to `path` as an HTML page, one box per notification coloured by its
+/// urgency, for looking over templates and strip rules in a browser.
+pub fn write(path: &str, notifications: &[(&Notification, Urgency)]) -> Result<(), String> {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>khal-notify preview</title>\n<style>\n{}\n</style>\n</head>\n<body>\n",
+        STYLE
+    );
+    if notifications.is_empty() {
+        html += "<p>No notifications.</p>\n";
+    }
+    for (notification, urgency) in notifications {
+        html += &format!(
+            "<div class=\"notification {urgency}\">\n<p class=\"title\">{}</p>\n<p class=\"body\">{}</p>\n<p class=\"urgency\">{urgency}</p>\n</div>\n",
+            escape(&notification.title),
+            body_html(&notification.body),
+            urgency = urgency.name()
+        );
+    }
+    html += "</body>\n</html>\n";
+    fs::write(path, html).map_err(|err| format!("could not write preview {}: {}", path, err))
+}
+
+/// Escapes `body` for HTML, apart from its anchors, which are made into links. An anchor with no
+/// text, as links from truncated descriptions are, shows its URL.
+fn body_html(body: &str) -> String {
+    let mut html = String::with_capacity(body.len());
+    let mut last = 0;
+    for anchor in ANCHOR_REGEX.captures_iter(body) {
+        let whole = anchor.get(0).unwrap();
+        html += &escape(&body[last..whole.start()]);
+        last = whole.end();
+        let url = &anchor[1];
+        let label = if anchor[2].is_empty() {
+            url
+        } else {
+            &anchor[2]
+        };
+        html += &format!("<a href=\"{}\">{}</a>", escape(url), escape(label));
+    }
+    html += &escape(&body[last..]);
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,56 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A spinner drawn on stderr until it's dropped.
+pub struct Spinner {
+    done: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    /// Starts a spinner next to `message`, or does nothing unless stderr is a terminal, so that
+    /// piped or redirected output never sees it.
+    pub fn start(message: &str) -> Spinner {
+        let done = Arc::new(AtomicBool::new(false));
+        if unsafe { libc::isatty(libc::STDERR_FILENO) } != 1 {
+            return Spinner { done, handle: None };
+        }
+        let message = message.to_owned();
+        let handle = thread::spawn({
+            let done = Arc::clone(&done);
+            move || {
+                let mut stderr = io::stderr();
+                for frame in FRAMES.iter().cycle() {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let _ = write!(stderr, "\r{} {}", frame, message);
+                    let _ = stderr.flush();
+                    thread::sleep(FRAME_INTERVAL);
+                }
+                // Clears the line so that whatever is printed next starts on a clean one.
+                let _ = write!(stderr, "\r\x1b[K");
+                let _ = stderr.flush();
+            }
+        });
+        Spinner {
+            done,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use crate::desc::{self, Markup};
+use crate::khal::KhalEvent;
+use crate::locale::{Locale, Phrase};
+use crate::template;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+use time::OffsetDateTime;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The body order used unless `--body-order` is given.
+pub const DEFAULT_BODY_ORDER: &str = "desc,links,time,attendees";
+/// The body order used with `--compact` unless `--body-order` is given.
+pub const COMPACT_BODY_ORDER: &str = "time,title,location";
+
+/// One of the pieces a notification body is assembled from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyPart {
+    Description,
+    /// Links from the truncated part of the description, which continue the line before them.
+    Links,
+    Time,
+    Title,
+    Location,
+    Attendees,
+    /// How long until the event starts, or since it did, like "in 10 min".
+    Relative,
+}
+
+impl FromStr for BodyPart {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "desc" => Ok(BodyPart::Description),
+            "links" => Ok(BodyPart::Links),
+            "time" => Ok(BodyPart::Time),
+            "title" => Ok(BodyPart::Title),
+            "location" => Ok(BodyPart::Location),
+            "attendees" => Ok(BodyPart::Attendees),
+            "relative" => Ok(BodyPart::Relative),
+            _ => Err(format!("unknown body component {:?}", s)),
+        }
+    }
+}
+
+/// What's done with titles that span several lines, which some daemons don't cope with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TitleLines {
+    /// Join the lines with spaces.
+    Collapse,
+    First,
+    /// Keep this many lines.
+    Count(usize),
+}
+
+impl TitleLines {
+    fn apply(self, title: &str) -> Cow<'_, str> {
+        if !title.contains('\n') {
+            return title.into();
+        }
+        let mut lines = title.lines().map(str::trim).filter(|line| !line.is_empty());
+        match self {
+            TitleLines::Collapse => lines.intersperse(" ").collect::<String>().into(),
+            TitleLines::First => lines.next().unwrap_or_default().into(),
+            TitleLines::Count(count) => lines
+                .take(count)
+                .intersperse("\n")
+                .collect::<String>()
+                .into(),
+        }
+    }
+}
+
+impl FromStr for TitleLines {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "collapse" => Ok(TitleLines::Collapse),
+            "first" => Ok(TitleLines::First),
+            _ => match s.parse() {
+                Ok(count) if count > 0 => Ok(TitleLines::Count(count)),
+                _ => Err(format!(
+                    "expected collapse, first or a number of lines, got {:?}",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// What's added to titles that several notifications would otherwise share.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DedupSuffix {
+    /// The start time, or the date for all-day events.
+    Time,
+    Calendar,
+}
+
+impl DedupSuffix {
+    fn text(self, event: &KhalEvent) -> &str {
+        match self {
+            DedupSuffix::Time if event.all_day => &event.start_date,
+            DedupSuffix::Time => &event.start_time,
+            DedupSuffix::Calendar => &event.calendar,
+        }
+    }
+}
+
+impl FromStr for DedupSuffix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "time" => Ok(DedupSuffix::Time),
+            "calendar" => Ok(DedupSuffix::Calendar),
+            _ => Err(format!("expected time or calendar, got {:?}", s)),
+        }
+    }
+}
+
+/// What goes into the body of each notification.
+pub struct Options {
+    /// The parts of the body in the order they're shown, leaving out any not listed.
+    pub body_order: Vec<BodyPart>,
+    /// How descriptions are shortened, or `None` to leave them out.
+    pub description: Option<desc::Options>,
+    /// How many attendees to list, or `None` to not list them.
+    pub max_attendees: Option<usize>,
+    /// Titles longer than this many graphemes are truncated, not counting the repeat symbol.
+    pub title_max_length: Option<usize>,
+    /// Titles with several lines are left as they are without this.
+    pub title_lines: Option<TitleLines>,
+    /// Added in brackets to titles that more than one of the events rendered together end up
+    /// with.
+    pub title_dedup_suffix: Option<DedupSuffix>,
+    /// Use the first line of the description as the title of events without one.
+    pub title_from_description: bool,
+    /// The title of events that are still without one.
+    pub default_title: Option<String>,
+    /// Put in front of the titles of events that have already started.
+    pub started_prefix: String,
+    /// Expanded with the event's fields and put in front of its title, unless it has no calendar.
+    pub calendar_prefix: Option<String>,
+    pub markup: Markup,
+    /// Puts the whole body on one line, with its parts joined by this.
+    pub compact_separator: Option<String>,
+    /// Put around the assembled body as they are, whatever the description was cut down to.
+    pub body_prefix: String,
+    pub body_suffix: String,
+    pub locale: Locale,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+}
+
+/// Renders `events` as the single notification sent by `--morning-digest`, with one line per
+/// event giving its start time, or that it's all day, and its title.
+pub fn render_digest(events: &[KhalEvent], title: String, locale: Locale) -> Notification {
+    let body = events
+        .iter()
+        .map(|event| {
+            let when = if event.all_day {
+                locale.text(Phrase::AllDay)
+            } else {
+                &event.start_time
+            };
+            format!("{} {}", when, event.title.replace('\n', " "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Notification { title, body }
+}
+
+/// Renders each of `events`, which are sent together, so that `--title-dedup-suffix` can tell
+/// apart those whose titles come out the same.
+pub fn render_events(events: &[KhalEvent], options: &Options) -> Vec<Notification> {
+    let mut notifications: Vec<Notification> = events
+        .iter()
+        .map(|event| render_event(event, options))
+        .collect();
+    if let Some(suffix) = options.title_dedup_suffix {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for notification in &notifications {
+            *counts.entry(notification.title.clone()).or_default() += 1;
+        }
+        for (notification, event) in notifications.iter_mut().zip(events) {
+            let text = suffix.text(event);
+            if counts[&notification.title] > 1 && !text.is_empty() {
+                notification.title = format!("{} ({})", notification.title, text);
+            }
+        }
+    }
+    notifications
+}
+
+pub fn render_event(event: &KhalEvent, options: &Options) -> Notification {
+    let (title, description_text, stripped) = title_and_description(event, options);
+    let title = render_title(event, &title, options);
+    let description = options.description.as_ref().map(|description| {
+        if stripped {
+            description.shorten_stripped(&description_text)
+        } else {
+            description.shorten(&description_text)
+        }
+    });
+    let mut body = String::new();
+    for &part in &options.body_order {
+        let text: Cow<'_, str> = match part {
+            BodyPart::Description => description.as_ref().map_or("".into(), |d| (&d.text).into()),
+            BodyPart::Links => description
+                .as_ref()
+                .map_or("".into(), |d| (&d.links).into()),
+            BodyPart::Time if event.all_day => "".into(),
+            BodyPart::Time => (&event.start_end_time_style).into(),
+            BodyPart::Title => (&title).into(),
+            BodyPart::Location => (&event.location).into(),
+            BodyPart::Attendees => match options.max_attendees {
+                Some(max) if !event.attendees.is_empty() => {
+                    format_attendees(&event.attendees, max, options.markup, options.locale).into()
+                }
+                _ => "".into(),
+            },
+            BodyPart::Relative => relative_time(event, options.locale).into(),
+        };
+        match (&options.compact_separator, part) {
+            (Some(separator), _) => push_segment(&mut body, &text, separator),
+            (None, BodyPart::Links) => {
+                if body.is_empty() || body.ends_with('\n') {
+                    body += text.trim_start();
+                } else {
+                    body += &text;
+                }
+            }
+            (None, _) => push_line(&mut body, &text),
+        }
+    }
+    let body = format!("{}{}{}", options.body_prefix, body, options.body_suffix);
+    Notification { title, body }
+}
+
+/// The event's title and the description left for the body, which differ from the event's own
+/// with `--title-from-desc` or `--default-title`, and whether that description was already
+/// stripped.
+fn title_and_description<'a>(
+    event: &'a KhalEvent,
+    options: &'a Options,
+) -> (Cow<'a, str>, Cow<'a, str>, bool) {
+    let mut title: Cow<'_, str> = (&event.title).into();
+    let mut description: Cow<'_, str> = (&event.description).into();
+    let mut stripped_already = false;
+    if options.title_from_description && event.title.trim().is_empty() {
+        let stripped = match &options.description {
+            Some(options) => options.strip(&event.description),
+            None => event.description.clone(),
+        };
+        let stripped = stripped.trim_start();
+        let (first, rest) = stripped.split_once('\n').unwrap_or((stripped, ""));
+        title = first.trim().to_owned().into();
+        description = rest.to_owned().into();
+        stripped_already = true;
+    }
+    if title.trim().is_empty() {
+        if let Some(default) = &options.default_title {
+            title = default.into();
+        }
+    }
+    (title, description, stripped_already)
+}
+
+fn render_title(event: &KhalEvent, text: &str, options: &Options) -> String {
+    let mut title = if event.started {
+        options.started_prefix.clone()
+    } else {
+        String::new()
+    };
+    if let Some(prefix) = &options.calendar_prefix {
+        if !event.calendar.is_empty() {
+            title += &template::expand(prefix, |name| event.field(name));
+            title += " ";
+        }
+    }
+    let text = match options.title_lines {
+        Some(title_lines) => title_lines.apply(text),
+        None => text.into(),
+    };
+    match options.title_max_length {
+        Some(max) => title += &truncate(&text, max),
+        None => title += &text,
+    }
+    if !event.repeat_symbol.is_empty() {
+        title += " ";
+        title += &event.repeat_symbol;
+    }
+    title
+}
+
+/// Cuts `text` to `max` graphemes, marking the cut with an ellipsis.
+fn truncate(text: &str, max: usize) -> String {
+    match text.grapheme_indices(true).nth(max) {
+        Some((cut, _)) => text[..cut].to_owned() + desc::ELLIPSIS,
+        None => text.to_owned(),
+    }
+}
+
+/// Appends `text` to a single line `body`, joining its own lines with spaces.
+fn push_segment(body: &mut String, text: &str, separator: &str) {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    let first = match lines.next() {
+        Some(first) => first,
+        None => return,
+    };
+    if !body.is_empty() {
+        body.push_str(separator);
+    }
+    body.push_str(first);
+    for line in lines {
+        body.push(' ');
+        body.push_str(line);
+    }
+}
+
+/// Appends `line` to `body`, starting a new line unless `body` is empty or already ends in one.
+fn push_line(body: &mut String, line: &str) {
+    if line.is_empty() {
+        return;
+    }
+    if !body.is_empty() && !body.ends_with('\n') {
+        body.push('\n');
+    }
+    body.push_str(line);
+}
+
+/// Lists the first `max` attendees, summarizing any others as a count.
+fn format_attendees(attendees: &[String], max: usize, markup: Markup, locale: Locale) -> String {
+    let mut listed: Vec<String> = attendees.iter().take(max).cloned().collect();
+    if attendees.len() > max {
+        listed.push(locale.count(Phrase::More, (attendees.len() - max) as i64));
+    }
+    let with = locale.text(Phrase::With);
+    match markup {
+        Markup::Markdown => listed
+            .iter()
+            .fold(with.to_owned(), |list, attendee| list + "\n- " + attendee),
+        Markup::Pango | Markup::Plain => format!("{} {}", with, listed.join(", ")),
+    }
+}
+
+/// Says how far off the event's start is, to the nearest minute.
+fn relative_time(event: &KhalEvent, locale: Locale) -> String {
+    if event.all_day {
+        return locale.text(Phrase::AllDay).to_owned();
+    }
+    let start = match event.start {
+        Some(start) => start,
+        None => return String::new(),
+    };
+    let seconds = (start - OffsetDateTime::now_utc()).whole_seconds();
+    match (seconds + 30).div_euclid(60) {
+        0 => locale.text(Phrase::Now).to_owned(),
+        minutes if minutes > 0 => locale.count(Phrase::InMinutes, minutes),
+        minutes => locale.count(Phrase::MinutesAgo, -minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            body_order: Vec::new(),
+            description: None,
+            max_attendees: None,
+            title_max_length: None,
+            title_lines: None,
+            title_dedup_suffix: None,
+            title_from_description: false,
+            default_title: None,
+            started_prefix: String::new(),
+            calendar_prefix: None,
+            markup: Markup::Plain,
+            compact_separator: None,
+            body_prefix: String::new(),
+            body_suffix: String::new(),
+            locale: Locale::default(),
+        }
+    }
+
+    fn event(title: &str) -> KhalEvent {
+        KhalEvent {
+            title: title.to_owned(),
+            ..KhalEvent::default()
+        }
+    }
+
+    #[test]
+    fn long_title_is_truncated() {
+        let options = Options {
+            title_max_length: Some(10),
+            ..options()
+        };
+        let event = event("Quarterly planning meeting");
+        assert_eq!(
+            render_title(&event, &event.title, &options),
+            "Quarterly ..."
+        );
+        let short = self::event("Standup");
+        assert_eq!(render_title(&short, &short.title, &options), "Standup");
+    }
+
+    #[test]
+    fn repeat_symbol_follows_ellipsis() {
+        let options = Options {
+            title_max_length: Some(10),
+            ..options()
+        };
+        let event = KhalEvent {
+            repeat_symbol: "⟳".to_owned(),
+            ..event("Quarterly planning meeting")
+        };
+        assert_eq!(
+            render_title(&event, &event.title, &options),
+            "Quarterly ... ⟳"
+        );
+    }
+
+    #[test]
+    fn started_event_has_prefix() {
+        let options = Options {
+            started_prefix: "(started) ".to_owned(),
+            ..options()
+        };
+        let started = KhalEvent {
+            started: true,
+            ..event("Standup")
+        };
+        assert_eq!(
+            render_title(&started, &started.title, &options),
+            "(started) Standup"
+        );
+        let upcoming = event("Standup");
+        assert_eq!(
+            render_title(&upcoming, &upcoming.title, &options),
+            "Standup"
+        );
+    }
+
+    #[test]
+    fn calendar_prefix_names_each_calendar() {
+        let options = Options {
+            calendar_prefix: Some("[{calendar}]".to_owned()),
+            ..options()
+        };
+        let work = KhalEvent {
+            calendar: "work".to_owned(),
+            ..event("Standup")
+        };
+        let home = KhalEvent {
+            calendar: "home".to_owned(),
+            ..event("Dinner")
+        };
+        assert_eq!(render_title(&work, &work.title, &options), "[work] Standup");
+        assert_eq!(render_title(&home, &home.title, &options), "[home] Dinner");
+    }
+
+    #[test]
+    fn no_calendar_prefix_without_calendar() {
+        let options = Options {
+            calendar_prefix: Some("[{calendar}]".to_owned()),
+            ..options()
+        };
+        let event = event("Standup");
+        assert_eq!(render_title(&event, &event.title, &options), "Standup");
+    }
+
+    #[test]
+    fn title_from_description_strips_once() {
+        let options = Options {
+            body_order: vec![BodyPart::Description],
+            description: Some(desc::Options {
+                strip_regexes: vec![(regex::Regex::new("pin").unwrap(), "PIN pin".to_owned())],
+                strip_order: desc::StripOrder::Before,
+                max_chars: None,
+                min_chars: 0,
+                links: false,
+                sort_links: false,
+                link_separator: " ".to_owned(),
+                link_mode: desc::LinkMode::Append,
+                markdown_links: false,
+                flatten_whitespace: false,
+                markup: Markup::Plain,
+            }),
+            title_from_description: true,
+            ..options()
+        };
+        let event = KhalEvent {
+            description: "Sync\ndial in, pin 1234".to_owned(),
+            ..event("")
+        };
+        let notification = render_event(&event, &options);
+        assert_eq!(notification.title, "Sync");
+        assert_eq!(notification.body, "dial in, PIN pin 1234");
+    }
+
+    #[test]
+    fn title_lines() {
+        let title = "Planning\n\n  Q3 roadmap \nRoom 4";
+        assert_eq!(
+            TitleLines::Collapse.apply(title),
+            "Planning Q3 roadmap Room 4"
+        );
+        assert_eq!(TitleLines::First.apply(title), "Planning");
+        assert_eq!(TitleLines::Count(2).apply(title), "Planning\nQ3 roadmap");
+        assert_eq!(
+            TitleLines::Count(5).apply(title),
+            "Planning\nQ3 roadmap\nRoom 4"
+        );
+        assert_eq!(TitleLines::First.apply("  Standup "), "  Standup ");
+    }
+
+    #[test]
+    fn title_lines_from_str() {
+        assert_eq!("collapse".parse(), Ok(TitleLines::Collapse));
+        assert_eq!("first".parse(), Ok(TitleLines::First));
+        assert_eq!("3".parse(), Ok(TitleLines::Count(3)));
+        assert!("0".parse::<TitleLines>().is_err());
+        assert!("-1".parse::<TitleLines>().is_err());
+        assert!("all".parse::<TitleLines>().is_err());
+    }
+}
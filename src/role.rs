@@ -0,0 +1,40 @@
+use crate::khal::KhalEvent;
+use std::str::FromStr;
+
+/// Which events `--role` keeps, by whether the user is the one organizing them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Organizer,
+    Attendee,
+    Any,
+}
+
+impl Role {
+    /// Whether `event` is kept, where `me` are the user's addresses to look for in its organizer.
+    ///
+    /// Events without an organizer, which includes every event from a khal too old to report it,
+    /// are kept whatever the role.
+    pub fn keeps(self, event: &KhalEvent, me: &[String]) -> bool {
+        if self == Role::Any || event.organizer.trim().is_empty() {
+            return true;
+        }
+        let organizer = event.organizer.to_lowercase();
+        let organizing = me
+            .iter()
+            .any(|address| organizer.contains(&address.to_lowercase()));
+        organizing == (self == Role::Organizer)
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "organizer" => Ok(Role::Organizer),
+            "attendee" => Ok(Role::Attendee),
+            "any" => Ok(Role::Any),
+            _ => Err(format!("unknown role {:?}", s)),
+        }
+    }
+}
@@ -0,0 +1,14 @@
+use std::borrow::Cow;
+
+/// Quotes `arg` for a POSIX shell, leaving it alone if it has nothing the shell would interpret.
+pub fn quote(arg: &str) -> Cow<'_, str> {
+    let is_plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:%=+@,".contains(c));
+    if is_plain {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
+}
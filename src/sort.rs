@@ -0,0 +1,47 @@
+use crate::khal::KhalEvent;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// What events are ordered by before they're notified or printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Start,
+    Title,
+    Calendar,
+    Duration,
+}
+
+impl SortKey {
+    /// Compares two events by this key, putting events whose key couldn't be worked out last.
+    pub fn compare(self, a: &KhalEvent, b: &KhalEvent) -> Ordering {
+        match self {
+            SortKey::Start => compare_known(a.start, b.start),
+            SortKey::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+            SortKey::Calendar => a.calendar.cmp(&b.calendar),
+            SortKey::Duration => compare_known(a.duration(), b.duration()),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "start" => Ok(SortKey::Start),
+            "title" => Ok(SortKey::Title),
+            "calendar" => Ok(SortKey::Calendar),
+            "duration" => Ok(SortKey::Duration),
+            _ => Err(format!("unknown sort key {:?}", s)),
+        }
+    }
+}
+
+fn compare_known<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
@@ -0,0 +1,134 @@
+use crate::khal::KhalEvent;
+use crate::render::Notification;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use time::{Format, OffsetDateTime};
+
+/// What's remembered between runs, kept in the file given by `--state-file`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    /// Event occurrences dismissed from their notification, by [`KhalEvent::id`], with
+    /// [`KhalEvent::listed_until`] so they can be forgotten once it has passed.
+    #[serde(default)]
+    pub dismissed: HashMap<String, Option<String>>,
+    /// Event occurrences notified with `--dedup`, by [`KhalEvent::id`], with when they may be
+    /// notified again.
+    #[serde(default)]
+    pub notified: HashMap<String, String>,
+    /// Notifications held back by `--quiet-hours` with `--defer-quiet`, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deferred: Vec<Deferred>,
+    /// [`event_set_hash`] of the events the last run with `--on-change` found.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_set: Option<String>,
+}
+
+/// A notification held back until quiet hours end.
+///
+/// The event itself isn't kept, only what's needed to send the notification the same way: its id
+/// so it isn't deferred twice, and its calendar for `--calendar-urgency`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Deferred {
+    pub id: String,
+    pub calendar: String,
+    pub title: String,
+    pub body: String,
+}
+
+/// Hashes which events there are and when they start, for `--on-change` to tell whether that's
+/// changed since the last run. Each event is keyed by its [`KhalEvent::id`] and start time, and
+/// the keys are sorted so that only the set counts, not the order.
+///
+/// This is FNV-1a rather than std's hasher, which isn't guaranteed to stay the same across Rust
+/// versions, and the hash is kept in the state file.
+pub fn event_set_hash(events: &[KhalEvent]) -> String {
+    let mut keys: Vec<String> = events
+        .iter()
+        .map(|event| {
+            let start = event.start.map(|start| start.format(Format::Rfc3339));
+            format!("{} {}", event.id(), start.unwrap_or_default())
+        })
+        .collect();
+    keys.sort_unstable();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in keys.join("\n").bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+impl State {
+    /// Reads the state from `path`, starting afresh if there's no file there yet.
+    pub fn load(path: &str) -> Result<State, String> {
+        match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|err| format!("could not parse state file {}: {}", path, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(format!("could not read state file {}: {}", path, err)),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let write = || -> io::Result<()> {
+            if let Some(dir) = Path::new(path).parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+        };
+        write().map_err(|err| format!("could not write state file {}: {}", path, err))
+    }
+
+    /// Forgets dismissed events listed only until before `earliest`, as khal won't list them again,
+    /// and notified events whose suppression has run out by `now`.
+    pub fn prune(&mut self, earliest: OffsetDateTime, now: OffsetDateTime) {
+        self.dismissed.retain(|_, start| {
+            start.as_ref().is_none_or(|start| {
+                OffsetDateTime::parse(start, Format::Rfc3339).is_ok_and(|start| start >= earliest)
+            })
+        });
+        self.notified.retain(|_, until| {
+            OffsetDateTime::parse(until, Format::Rfc3339).is_ok_and(|until| until >= now)
+        });
+    }
+
+    /// Dismisses only this occurrence of `event`, so later ones of a recurring event still notify.
+    pub fn dismiss(&mut self, event: &KhalEvent) {
+        self.dismissed.insert(
+            event.id(),
+            event
+                .listed_until()
+                .map(|until| until.format(Format::Rfc3339)),
+        );
+    }
+
+    pub fn is_dismissed(&self, event: &KhalEvent) -> bool {
+        self.dismissed.contains_key(&event.id())
+    }
+
+    /// Keeps `event` from being notified again until `until`.
+    pub fn notified(&mut self, event: &KhalEvent, until: OffsetDateTime) {
+        self.notified
+            .insert(event.id(), until.format(Format::Rfc3339));
+    }
+
+    pub fn was_notified(&self, event: &KhalEvent) -> bool {
+        self.notified.contains_key(&event.id())
+    }
+
+    /// Holds back `notification` for `event` until quiet hours end, unless it already is.
+    pub fn defer(&mut self, event: &KhalEvent, notification: &Notification) {
+        let id = event.id();
+        if self.deferred.iter().all(|deferred| deferred.id != id) {
+            self.deferred.push(Deferred {
+                id,
+                calendar: event.calendar.clone(),
+                title: notification.title.clone(),
+                body: notification.body.clone(),
+            });
+        }
+    }
+}
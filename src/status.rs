@@ -0,0 +1,31 @@
+use serde::Serialize;
+use std::time::Duration;
+use std::{fs, io};
+use time::{Format, OffsetDateTime};
+
+/// What a single run did, written out for monitoring with `--status-file`.
+#[derive(Serialize, Debug, Default)]
+pub struct Status {
+    pub timestamp: String,
+    /// Events that passed every filter.
+    pub matched: usize,
+    /// Matched events whose notification was sent.
+    pub notified: usize,
+    /// Events khal returned that were filtered out.
+    pub skipped: usize,
+    pub error: Option<String>,
+    /// How long reading the events took, for `--max-runtime-warn`.
+    #[serde(skip)]
+    pub fetch_time: Duration,
+    /// How long sending the notifications took.
+    #[serde(skip)]
+    pub dispatch_time: Duration,
+}
+
+impl Status {
+    /// Stamps the status with the current time and writes it to `path` as JSON.
+    pub fn write(&mut self, path: &str) -> io::Result<()> {
+        self.timestamp = OffsetDateTime::now_utc().format(Format::Rfc3339);
+        fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+    }
+}
@@ -0,0 +1,116 @@
+/// Expands `{name}` placeholders in `template` using `lookup`.
+///
+/// Unknown placeholders expand to nothing, and `{{` and `}}` stand for literal braces.
+/// `{name?text}` expands `text`, which can hold placeholders of its own, only if `name` is
+/// non-empty, as in `{location?at {location}}`.
+pub fn expand(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    expand_with(template, &lookup)
+}
+
+fn expand_with(template: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..i]);
+        let brace = rest.as_bytes()[i];
+        rest = &rest[i + 1..];
+        if rest.as_bytes().first() == Some(&brace) {
+            expanded.push(brace as char);
+            rest = &rest[1..];
+        } else if brace == b'{' {
+            match closing_brace(rest) {
+                Some(end) => {
+                    match rest[..end].split_once('?') {
+                        Some((name, text)) => {
+                            if lookup(name).is_some_and(|value| !value.is_empty()) {
+                                expanded.push_str(&expand_with(text, lookup));
+                            }
+                        }
+                        None => expanded.push_str(&lookup(&rest[..end]).unwrap_or_default()),
+                    }
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    expanded.push('{');
+                }
+            }
+        } else {
+            expanded.push('}');
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Finds the `}` closing a placeholder that `text` is the inside of, skipping over the
+/// placeholders nested in a conditional's text and its `{{` and `}}` escapes the same way
+/// [`expand`] reads them.
+fn closing_brace(text: &str) -> Option<usize> {
+    let name_end = text.find(['?', '}'])?;
+    if text.as_bytes()[name_end] == b'}' {
+        return Some(name_end);
+    }
+    let bytes = text.as_bytes();
+    let mut i = name_end + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'{' | b'}' if bytes.get(i + 1) == Some(&bytes[i]) => i += 1,
+            b'{' => i += 1 + closing_brace(&text[i + 1..])?,
+            b'}' => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "title" => Some("Standup".to_owned()),
+            "location" => Some("Room 4".to_owned()),
+            "description" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn placeholders_and_escapes() {
+        assert_eq!(expand("{title} in {location}", lookup), "Standup in Room 4");
+        assert_eq!(expand("{unknown}{title}", lookup), "Standup");
+        assert_eq!(expand("{{title}} {title}", lookup), "{title} Standup");
+        assert_eq!(expand("open { brace", lookup), "open { brace");
+    }
+
+    #[test]
+    fn conditional_on_present_field() {
+        assert_eq!(
+            expand("{title}{location? at {location}}", lookup),
+            "Standup at Room 4"
+        );
+    }
+
+    #[test]
+    fn conditional_on_absent_field() {
+        assert_eq!(
+            expand("{title}{description? - {description}}", lookup),
+            "Standup"
+        );
+        assert_eq!(expand("{title}{unknown? ({unknown})}", lookup), "Standup");
+    }
+
+    #[test]
+    fn nested_conditionals_and_escapes() {
+        assert_eq!(
+            expand("{location?{title?{{{title}}} }at {location}}", lookup),
+            "{Standup} at Room 4"
+        );
+        assert_eq!(
+            expand("{location?{description?never }shown}", lookup),
+            "shown"
+        );
+    }
+}
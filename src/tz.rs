@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::env;
+use std::mem::MaybeUninit;
+use std::path::Path;
+use time::{OffsetDateTime, UtcOffset};
+
+extern "C" {
+    // Not bound by the libc crate.
+    fn tzset();
+}
+
+/// The current offset of the local timezone, which is `TZ` when that's set and the system's
+/// timezone otherwise.
+///
+/// The offset is the one in effect now, so events on the other side of a daylight saving change
+/// are off by the difference.
+pub fn local_offset() -> Option<UtcOffset> {
+    let now = OffsetDateTime::now_utc().unix_timestamp() as libc::time_t;
+    let mut tm = MaybeUninit::<libc::tm>::uninit();
+    // libc resolves TZ, including IANA names, and the system timezone itself. localtime_r writes to
+    // the tm it's given rather than shared state, and fills it in completely when it succeeds.
+    let tm = unsafe {
+        tzset();
+        if libc::localtime_r(&now, tm.as_mut_ptr()).is_null() {
+            return None;
+        }
+        tm.assume_init()
+    };
+    Some(UtcOffset::seconds(tm.tm_gmtoff as i32))
+}
+
+/// The current offset of the IANA timezone `zone`, like `Europe/Berlin`, or of a fixed offset in
+/// hours, like `--timezone` takes.
+///
+/// libc only looks up the timezone in `TZ`, so it's pointed at `zone` for the lookup and then put
+/// back. libc reads the environment without Rust's lock, so this must only be called before any
+/// other threads are started. The zone has to exist in the system's zoneinfo, as libc takes
+/// unknown names as UTC.
+pub fn zone_offset(zone: &str) -> Option<UtcOffset> {
+    if let Ok(hours) = zone.parse::<i8>() {
+        return Some(UtcOffset::hours(hours));
+    }
+    let zoneinfo = env::var("TZDIR").unwrap_or_else(|_| "/usr/share/zoneinfo".to_owned());
+    if zone.starts_with('/') || zone.contains("..") || !Path::new(&zoneinfo).join(zone).is_file() {
+        return None;
+    }
+    let previous = env::var_os("TZ");
+    env::set_var("TZ", zone);
+    let offset = local_offset();
+    match previous {
+        Some(previous) => env::set_var("TZ", previous),
+        None => env::remove_var("TZ"),
+    }
+    unsafe { tzset() };
+    offset
+}
+
+/// Parses `--calendar-tz`, giving each calendar's zone, named as it's shown, along with its
+/// current offset. Like [`zone_offset`], this must be called before any other threads are started.
+pub fn parse_calendar_tz(mapping: &str) -> Result<HashMap<String, (String, UtcOffset)>, String> {
+    mapping
+        .split(',')
+        .map(|pair| {
+            let (calendar, zone) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected CALENDAR=ZONE, got {:?}", pair))?;
+            let offset = zone_offset(zone).ok_or_else(|| format!("unknown timezone {:?}", zone))?;
+            let name = match zone.parse::<i8>() {
+                Ok(hours) => format!("UTC{:+}", hours),
+                Err(_) => zone.to_owned(),
+            };
+            Ok((calendar.to_owned(), (name, offset)))
+        })
+        .collect()
+}
@@ -0,0 +1,27 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::{env, fs};
+
+/// Finds the executable `program`, looking it up in `PATH` unless it's already a path.
+pub fn find(program: &str) -> Result<PathBuf, String> {
+    if program.contains('/') {
+        return Ok(PathBuf::from(program));
+    }
+    let dirs: Vec<PathBuf> = env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect())
+        .unwrap_or_default();
+    dirs.iter()
+        .map(|dir| dir.join(program))
+        .find(|candidate| {
+            fs::metadata(candidate)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+        .ok_or_else(|| {
+            let searched: String = dirs
+                .iter()
+                .map(|dir| dir.to_string_lossy())
+                .intersperse(", ".into())
+                .collect();
+            format!("{} not found on PATH (searched: {})", program, searched)
+        })
+}
@@ -0,0 +1,102 @@
+//! Runs khal-notify against stub khal and notify commands, to check what it does when they fail.
+
+mod common;
+
+use common::Scratch;
+use std::fs;
+use std::process::{Command, Output};
+
+/// Prints a single event, as khal would, for any query.
+const KHAL: &str = r#"cat <<'JSON'
+[{"title": "Vendor call", "description": "", "location": "", "uid": "v1", "calendar": "work",
+  "start-date": "2030-06-03", "start-time": "10:00", "end-date": "2030-06-03",
+  "end-time": "10:30", "start-end-time-style": "10:00-10:30", "repeat-symbol": "",
+  "all-day": false}]
+JSON"#;
+
+fn run(scratch: &Scratch, notify: &str, args: &[&str]) -> Output {
+    let khal = scratch.script("khal", KHAL);
+    let notify = scratch.script("notify", notify);
+    Command::new(common::binary())
+        .env("TZ", "UTC")
+        .arg("--khal-path")
+        .arg(khal)
+        .arg("--notify-cmd")
+        .arg(notify)
+        .arg("--state-file")
+        .arg(scratch.path("state"))
+        .args(["--timezone", "0"])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn failed_notification_is_not_deduplicated() {
+    let scratch = Scratch::new("failed-dedup");
+    let output = run(&scratch, "exit 1", &["--dedup"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"Vendor call\""), "{}", stderr);
+    assert!(stderr.contains("exited with code 1"), "{}", stderr);
+    let state = fs::read_to_string(scratch.path("state")).unwrap_or_default();
+    assert!(!state.contains("v1"), "{}", state);
+
+    let output = run(&scratch, "exit 0", &["--dedup"]);
+    assert!(output.status.success());
+    let state = fs::read_to_string(scratch.path("state")).unwrap();
+    assert!(state.contains("v1"), "{}", state);
+}
+
+#[test]
+fn status_file_records_failed_send() {
+    let scratch = Scratch::new("failed-status");
+    let status_file = scratch.path("status.json");
+    let output = run(
+        &scratch,
+        "exit 1",
+        &["--status-file", status_file.to_str().unwrap()],
+    );
+    assert!(!output.status.success());
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&status_file).unwrap()).unwrap();
+    assert_eq!(status["matched"], 1);
+    assert_eq!(status["notified"], 0);
+    let error = status["error"].as_str().unwrap();
+    assert!(error.contains("\"Vendor call\""), "{}", error);
+
+    run(
+        &scratch,
+        "exit 0",
+        &["--status-file", status_file.to_str().unwrap()],
+    );
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&status_file).unwrap()).unwrap();
+    assert_eq!(status["notified"], 1);
+    assert!(status["error"].is_null());
+}
+
+#[test]
+fn audit_log_leaves_out_failed_send() {
+    let scratch = Scratch::new("failed-audit");
+    let audit_log = scratch.path("audit.log");
+    let args = ["--audit-log", audit_log.to_str().unwrap()];
+    run(&scratch, "exit 1", &args);
+    let logged = fs::read_to_string(&audit_log).unwrap_or_default();
+    assert!(!logged.contains("Vendor call"), "{}", logged);
+
+    run(&scratch, "exit 0", &args);
+    let logged = fs::read_to_string(&audit_log).unwrap();
+    assert_eq!(logged.matches("Vendor call").count(), 1, "{}", logged);
+}
+
+#[test]
+fn failed_notification_reports_stderr() {
+    let scratch = Scratch::new("failed-stderr");
+    let output = run(&scratch, "echo 'cannot reach daemon' >&2; exit 3", &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"Vendor call\""), "{}", stderr);
+    assert!(stderr.contains("exited with code 3"), "{}", stderr);
+    assert!(stderr.contains("cannot reach daemon"), "{}", stderr);
+}
@@ -0,0 +1,80 @@
+//! Golden-file checks for the render pipeline. Each `fixtures/NAME.json` stands in for khal's
+//! output and is run through khal-notify with the options in `fixtures/NAME.args`, one per line,
+//! and the notifications it prints are compared with `fixtures/NAME.expected`. They're printed
+//! with `--output json-compact`, unless `fixtures/NAME.output` names another mode.
+//!
+//! Run with `UPDATE_GOLDEN=1 cargo test --test golden` to rewrite the expectations after a change
+//! that's meant to alter the output.
+
+mod common;
+
+use common::Scratch;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn golden_files() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let scratch = Scratch::new("golden");
+    // Stands in for khal, whatever it's asked, by printing the fixture.
+    let khal = scratch.script("khal", r#"cat "$GOLDEN_FIXTURE""#);
+    let update = env::var_os("UPDATE_GOLDEN").is_some_and(|update| !update.is_empty());
+
+    let mut names: Vec<String> = fs::read_dir(&fixtures)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_suffix(".args").map(str::to_owned)
+        })
+        .collect();
+    names.sort();
+    assert!(!names.is_empty(), "no fixtures in {}", fixtures.display());
+
+    let mut failed = Vec::new();
+    for name in &names {
+        let fixture = |extension: &str| fixtures.join(format!("{}.{}", name, extension));
+        let args = fs::read_to_string(fixture("args")).unwrap();
+        let mode = fs::read_to_string(fixture("output"))
+            .map_or_else(|_| "json-compact".to_owned(), |mode| mode.trim().to_owned());
+        // The fixtures are far enough in the future that no event counts as started, and the
+        // state file is a fresh one, so nothing but the options decides the output.
+        let output = Command::new(common::binary())
+            .env("TZ", "UTC")
+            .env("GOLDEN_FIXTURE", fixture("json"))
+            .arg("--khal-path")
+            .arg(&khal)
+            .arg("--state-file")
+            .arg(scratch.path(&format!("{}.state", name)))
+            .args(["--timezone", "0", "--output", &mode])
+            .args(args.lines())
+            .output()
+            .unwrap();
+        if !output.status.success() {
+            eprintln!(
+                "{}: khal-notify failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            failed.push(name);
+            continue;
+        }
+        let actual = String::from_utf8(output.stdout).unwrap();
+        let actual = format!("{}\n", actual.trim_end_matches('\n'));
+        if update {
+            fs::write(fixture("expected"), &actual).unwrap();
+            eprintln!("updated {}", name);
+            continue;
+        }
+        let expected = fs::read_to_string(fixture("expected")).unwrap_or_default();
+        if actual != expected {
+            eprintln!(
+                "{}: output differs\n--- expected\n{}--- actual\n{}",
+                name, expected, actual
+            );
+            failed.push(name);
+        }
+    }
+    assert!(failed.is_empty(), "golden files differ: {:?}", failed);
+}
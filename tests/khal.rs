@@ -0,0 +1,50 @@
+//! Runs khal-notify against stub khal commands, to check how it queries khal and handles failures.
+
+mod common;
+
+use common::Scratch;
+use std::fs;
+use std::process::{Command, Output};
+
+fn run(scratch: &Scratch, khal: &str) -> Output {
+    let khal = scratch.script("khal", khal);
+    Command::new(common::binary())
+        .env("TZ", "UTC")
+        .arg("--khal-path")
+        .arg(khal)
+        .arg("--state-file")
+        .arg(scratch.path("state"))
+        .args(["--timezone", "0", "--output", "json-compact"])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn failed_khal_is_reported_with_stderr() {
+    let scratch = Scratch::new("khal-failed");
+    let output = run(&scratch, "echo 'unknown calendar' >&2; exit 1");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("khal exited with code 1"), "{}", stderr);
+    assert!(stderr.contains("unknown calendar"), "{}", stderr);
+}
+
+#[test]
+fn khal_is_asked_for_json() {
+    let scratch = Scratch::new("khal-args");
+    let args = scratch.path("args");
+    let output = run(
+        &scratch,
+        &format!("echo \"$@\" > '{}'; echo '[]'", args.display()),
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "[]");
+    let args = fs::read_to_string(args).unwrap();
+    assert!(args.contains(" at "), "{}", args);
+    assert!(args.contains("--json title --json description"), "{}", args);
+}